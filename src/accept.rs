@@ -0,0 +1,91 @@
+use crate::ApiResponse;
+use crate::config::STRICT_ACCEPT;
+
+use axum::{
+    Json,
+    extract::{MatchedPath, Request},
+    http::{StatusCode, header::ACCEPT},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Media types produced by the JSON endpoints; errors use the same body shape.
+const JSON_TYPES: &[&str] = &["application/json", "application/problem+json"];
+
+/// Media types a route can respond with, keyed by its route pattern.
+fn produced_types(route: Option<&str>) -> &'static [&'static str] {
+    match route {
+        Some("/") | Some("/metrics") => &["text/plain"],
+        Some("/identity/events") => &["text/event-stream"],
+        _ => JSON_TYPES,
+    }
+}
+
+/// Whether an `Accept` header value admits `media_type`. Ranges may use
+/// wildcards (`*/*`, `application/*`), and a range with `q=0` refuses the type.
+fn accepts(accept: &str, media_type: &str) -> bool {
+    let (kind, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+
+    accept.split(',').any(|range| {
+        let mut parts = range.split(';').map(str::trim);
+        let range = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let refused = parts.any(|param| {
+            param.split_once('=').is_some_and(|(name, value)| {
+                name.trim() == "q" && value.trim().parse::<f32>() == Ok(0.0)
+            })
+        });
+
+        let matches = range == "*/*"
+            || range == media_type
+            || range
+                .strip_suffix("/*")
+                .is_some_and(|range_kind| range_kind == kind);
+        matches && !refused
+    })
+}
+
+/// In `STRICT_ACCEPT` mode, answers `406 Not Acceptable`, listing the supported
+/// types, when the `Accept` header admits none of the types the route produces.
+/// A missing or empty `Accept` header accepts anything.
+pub async fn require_acceptable(
+    matched_path: Option<MatchedPath>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let supported = produced_types(matched_path.as_ref().map(MatchedPath::as_str));
+    let accept = req
+        .headers()
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<&str>>()
+        .join(",");
+
+    if *STRICT_ACCEPT
+        && !accept.trim().is_empty()
+        && !supported
+            .iter()
+            .any(|media_type| accepts(&accept, media_type))
+    {
+        let response_data = ApiResponse::new("Not acceptable", supported);
+        return (StatusCode::NOT_ACCEPTABLE, Json(response_data)).into_response();
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_matches_exact_types_and_wildcards() {
+        assert!(accepts("application/json", "application/json"));
+        assert!(accepts("text/html, */*;q=0.1", "application/json"));
+        assert!(accepts("application/*", "application/json"));
+        assert!(accepts("Application/JSON", "application/json"));
+        assert!(!accepts("text/html", "application/json"));
+        assert!(!accepts("text/*", "application/json"));
+        assert!(!accepts("application/json;q=0", "application/json"));
+    }
+}
@@ -0,0 +1,65 @@
+use mongodb::bson::{Bson, DateTime, Document, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// One recorded change to an identity, stored in the `identity_audit` collection.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub identity_id: ObjectId,
+    /// Email of the user who made the change.
+    pub actor: String,
+    pub at: DateTime,
+    pub changes: BTreeMap<String, FieldChange>,
+}
+
+/// Value of a field before and after a change; `None` when the field was absent.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub before: Option<Bson>,
+    pub after: Option<Bson>,
+}
+
+/// Compares the fields of a `$set` against the document it was applied to,
+/// keeping only the ones whose value actually changed. An absent field and an
+/// empty array count as equal, as they do when an identity's `labels` is read.
+pub fn diff(before: &Document, set: &Document) -> BTreeMap<String, FieldChange> {
+    set.iter()
+        .filter(|(field, after)| match before.get(field.as_str()) {
+            Some(previous) => previous != *after,
+            None => !matches!(after, Bson::Array(values) if values.is_empty()),
+        })
+        .map(|(field, after)| {
+            let change = FieldChange {
+                before: before.get(field.as_str()).cloned(),
+                after: Some(after.clone()),
+            };
+            (field.clone(), change)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mongodb::bson::doc;
+
+    #[test]
+    fn diff_keeps_only_changed_fields() {
+        let before = doc! { "name": "Alice", "age": 30 };
+        let set = doc! { "name": "Alice", "age": 31, "labels": ["vip"] };
+
+        let changes = diff(&before, &set);
+        assert_eq!(
+            changes.keys().map(String::as_str).collect::<Vec<_>>(),
+            ["age", "labels"]
+        );
+        assert_eq!(changes["age"].before, Some(Bson::Int32(30)));
+        assert_eq!(changes["age"].after, Some(Bson::Int32(31)));
+        assert_eq!(changes["labels"].before, None);
+        assert!(diff(&before, &doc! { "name": "Alice" }).is_empty());
+        assert!(diff(&before, &doc! { "name": "Alice", "labels": [] }).is_empty());
+    }
+}
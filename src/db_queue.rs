@@ -0,0 +1,98 @@
+use crate::error::AppError;
+
+use axum::{
+    extract::{Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+/// Bounded queue in front of the MongoDB connection pool. The driver accepts
+/// `waitQueueTimeoutMS` but never enforces it, so a checkout on a saturated pool
+/// waits indefinitely; admitting at most one request per pooled connection and
+/// timing out the wait for a slot gives clients a fast `503` instead.
+pub struct DbWaitQueue {
+    slots: Semaphore,
+    timeout: Duration,
+}
+
+impl DbWaitQueue {
+    pub fn new(slots: u32, timeout: Duration) -> Self {
+        DbWaitQueue {
+            slots: Semaphore::new(slots.max(1) as usize),
+            timeout,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.timeout.is_zero()
+    }
+}
+
+/// Holds a queue slot while the handler runs, answering `503 Service Unavailable`
+/// when none frees up within the configured wait queue timeout.
+pub async fn db_wait_queue(
+    State(queue): State<Arc<DbWaitQueue>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !queue.is_enabled() {
+        return next.run(req).await;
+    }
+
+    let _slot = match tokio::time::timeout(queue.timeout, queue.slots.acquire()).await {
+        Ok(Ok(slot)) => slot,
+        Ok(Err(e)) => return AppError::internal(e).into_response(),
+        Err(_) => {
+            warn!(
+                timeout_ms = queue.timeout.as_millis() as u64,
+                "Timed out waiting for a database connection"
+            );
+            let mut response = AppError::Unavailable.into_response();
+            response
+                .headers_mut()
+                .insert("Retry-After", HeaderValue::from_static("1"));
+            return response;
+        }
+    };
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{
+        Router,
+        body::Body,
+        http::{Request, StatusCode},
+        middleware::from_fn_with_state,
+        routing::get,
+    };
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn answers_503_while_every_slot_is_held() {
+        let queue = Arc::new(DbWaitQueue::new(1, Duration::from_millis(20)));
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route_layer(from_fn_with_state(Arc::clone(&queue), db_wait_queue));
+
+        let held = queue.slots.acquire().await.unwrap();
+        let response = app
+            .clone()
+            .oneshot(Request::new(Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["Retry-After"], "1");
+
+        drop(held);
+        let response = app.oneshot(Request::new(Body::empty())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
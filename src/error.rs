@@ -0,0 +1,199 @@
+use crate::ApiResponse;
+use crate::request_id::current_request_id;
+
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use mongodb::error::{Error, ErrorKind, WriteFailure};
+use std::{fmt::Display, io};
+use tracing::{error, warn};
+
+const DUPLICATE_KEY: i32 = 11000;
+const NAMESPACE_NOT_FOUND: i32 = 26;
+const INDEX_NOT_FOUND: i32 = 27;
+const MAX_TIME_MS_EXPIRED: i32 = 50;
+
+/// Failures a handler can surface, each mapped to a status and an `ApiResponse` body.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    Unprocessable(String),
+    Timeout,
+    Unavailable,
+    Internal,
+}
+
+impl AppError {
+    /// Logs an unexpected failure and hides its details from the client.
+    pub fn internal(e: impl Display) -> Self {
+        error!("Internal Server Error : {}", e);
+        AppError::Internal
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            AppError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::NotFound(message)
+            | AppError::Conflict(message)
+            | AppError::Unprocessable(message) => message.clone(),
+            AppError::Timeout => "Database timed out".to_string(),
+            AppError::Unavailable => "Database unavailable".to_string(),
+            AppError::Internal => "Internal Server Error".to_string(),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let mut response_data = ApiResponse::new(self.message(), ());
+        if status.is_server_error() {
+            response_data.request_id = current_request_id();
+        }
+        (status, Json(response_data)).into_response()
+    }
+}
+
+/// Maps a driver error to the `AppError` a client should see: duplicate keys
+/// become `409`, timeouts `504`, connectivity problems `503` and the rest `500`.
+pub fn classify_db_error(e: &Error) -> AppError {
+    let classified = match (server_error_code(e), e.kind.as_ref()) {
+        (Some(DUPLICATE_KEY), _) => AppError::Conflict("Duplicate key".to_string()),
+        (Some(MAX_TIME_MS_EXPIRED), _) => AppError::Timeout,
+        (_, ErrorKind::Io(io_error)) if io_error.kind() == io::ErrorKind::TimedOut => {
+            AppError::Timeout
+        }
+        (_, ErrorKind::Io(_))
+        | (_, ErrorKind::ServerSelection { .. })
+        | (_, ErrorKind::ConnectionPoolCleared { .. }) => AppError::Unavailable,
+        _ => AppError::Internal,
+    };
+
+    match classified {
+        AppError::Internal => error!("Database error : {}", e),
+        _ => warn!("Database error ({}) : {}", classified.status(), e),
+    }
+    classified
+}
+
+/// Whether a write failed on a unique index.
+pub fn is_duplicate_key(e: &Error) -> bool {
+    server_error_code(e) == Some(DUPLICATE_KEY)
+}
+
+/// Whether a `dropIndex` failed because the index, or its whole collection,
+/// does not exist.
+pub fn is_missing_index(e: &Error) -> bool {
+    matches!(
+        server_error_code(e),
+        Some(NAMESPACE_NOT_FOUND | INDEX_NOT_FOUND)
+    )
+}
+
+fn server_error_code(e: &Error) -> Option<i32> {
+    match e.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) => Some(write_error.code),
+        ErrorKind::Command(command_error) => Some(command_error.code),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mongodb::{
+        Client,
+        bson::{doc, from_document},
+        error::{CommandError, WriteError},
+    };
+
+    fn command_error(code: i32) -> Error {
+        let error: CommandError = from_document(doc! { "code": code }).unwrap();
+        Error::from(ErrorKind::Command(error))
+    }
+
+    fn write_error(code: i32) -> Error {
+        let error: WriteError = from_document(doc! { "code": code }).unwrap();
+        Error::from(ErrorKind::Write(WriteFailure::WriteError(error)))
+    }
+
+    #[test]
+    fn classifies_server_error_codes() {
+        assert_eq!(
+            classify_db_error(&write_error(DUPLICATE_KEY)).status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            classify_db_error(&command_error(DUPLICATE_KEY)).status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            classify_db_error(&command_error(MAX_TIME_MS_EXPIRED)).status(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(
+            classify_db_error(&command_error(2)).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert!(is_duplicate_key(&write_error(DUPLICATE_KEY)));
+        assert!(!is_duplicate_key(&write_error(2)));
+        assert!(is_missing_index(&command_error(INDEX_NOT_FOUND)));
+        assert!(is_missing_index(&command_error(NAMESPACE_NOT_FOUND)));
+        assert!(!is_missing_index(&command_error(DUPLICATE_KEY)));
+    }
+
+    #[test]
+    fn classifies_io_errors() {
+        let timed_out = Error::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(
+            classify_db_error(&timed_out).status(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        let refused = Error::from(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert_eq!(
+            classify_db_error(&refused).status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn classifies_server_selection_failures() {
+        let client = Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+            .await
+            .unwrap();
+        let error = client
+            .database("restful_axum_test")
+            .run_command(doc! { "ping": 1 })
+            .await
+            .unwrap_err();
+
+        assert!(matches!(*error.kind, ErrorKind::ServerSelection { .. }));
+        assert_eq!(
+            classify_db_error(&error).status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use crate::config::LOCALIZE_MESSAGES;
+
+use axum::{
+    extract::Request,
+    http::{
+        HeaderValue,
+        header::{ACCEPT_LANGUAGE, CONTENT_LANGUAGE, CONTENT_TYPE, VARY},
+    },
+    middleware::Next,
+    response::Response,
+};
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Languages with a catalog entry, in the column order of `CATALOG`; English
+/// is the language messages are written in.
+const LANGUAGES: &[&str] = &["en", "es", "fr", "de"];
+
+/// Translations of response messages. The English message doubles as the
+/// message id; messages without an entry are returned in English.
+const CATALOG: &[[&str; 4]] = &[
+    [
+        "Not found",
+        "No encontrado",
+        "Introuvable",
+        "Nicht gefunden",
+    ],
+    [
+        "Internal Server Error",
+        "Error interno del servidor",
+        "Erreur interne du serveur",
+        "Interner Serverfehler",
+    ],
+    [
+        "Database unavailable",
+        "Base de datos no disponible",
+        "Base de données indisponible",
+        "Datenbank nicht verfügbar",
+    ],
+    [
+        "Database timed out",
+        "La base de datos no respondió a tiempo",
+        "La base de données n'a pas répondu à temps",
+        "Zeitüberschreitung der Datenbank",
+    ],
+    [
+        "Request timed out",
+        "La solicitud excedió el tiempo de espera",
+        "La requête a expiré",
+        "Zeitüberschreitung der Anfrage",
+    ],
+    [
+        "Not acceptable",
+        "No aceptable",
+        "Non acceptable",
+        "Nicht akzeptabel",
+    ],
+    [
+        "Identity created",
+        "Identidad creada",
+        "Identité créée",
+        "Identität erstellt",
+    ],
+    [
+        "Identity does not exist",
+        "La identidad no existe",
+        "L'identité n'existe pas",
+        "Die Identität existiert nicht",
+    ],
+    [
+        "Document not found",
+        "Documento no encontrado",
+        "Document introuvable",
+        "Dokument nicht gefunden",
+    ],
+    ["Fetched", "Obtenido", "Récupéré", "Abgerufen"],
+    [
+        "Fetched all identities",
+        "Se obtuvieron todas las identidades",
+        "Toutes les identités ont été récupérées",
+        "Alle Identitäten abgerufen",
+    ],
+    [
+        "Fetched your identities",
+        "Se obtuvieron tus identidades",
+        "Vos identités ont été récupérées",
+        "Deine Identitäten wurden abgerufen",
+    ],
+    ["Updated", "Actualizado", "Mis à jour", "Aktualisiert"],
+    [
+        "No changes made",
+        "No se realizaron cambios",
+        "Aucune modification effectuée",
+        "Keine Änderungen vorgenommen",
+    ],
+    ["Deleted", "Eliminado", "Supprimé", "Gelöscht"],
+    [
+        "Permanently deleted",
+        "Eliminado permanentemente",
+        "Supprimé définitivement",
+        "Endgültig gelöscht",
+    ],
+    [
+        "Auth created",
+        "Cuenta creada",
+        "Compte créé",
+        "Konto erstellt",
+    ],
+    [
+        "Email already registered",
+        "El correo ya está registrado",
+        "Adresse e-mail déjà enregistrée",
+        "E-Mail-Adresse bereits registriert",
+    ],
+    [
+        "Invalid email or password",
+        "Correo o contraseña no válidos",
+        "Adresse e-mail ou mot de passe invalide",
+        "Ungültige E-Mail-Adresse oder ungültiges Passwort",
+    ],
+    [
+        "You are logged in",
+        "Has iniciado sesión",
+        "Vous êtes connecté",
+        "Du bist angemeldet",
+    ],
+    [
+        "Admin access required",
+        "Se requiere acceso de administrador",
+        "Accès administrateur requis",
+        "Administratorzugriff erforderlich",
+    ],
+    [
+        "Server time",
+        "Hora del servidor",
+        "Heure du serveur",
+        "Serverzeit",
+    ],
+];
+
+/// Language negotiated for a request, and whether any message was translated into it.
+struct Negotiated {
+    language: &'static str,
+    translated: Arc<AtomicBool>,
+}
+
+tokio::task_local! {
+    static LANGUAGE: Negotiated;
+}
+
+/// Translates `message` into the language negotiated for the current request,
+/// falling back to the message itself.
+pub fn localize(message: String) -> String {
+    LANGUAGE
+        .try_with(|negotiated| {
+            let column = LANGUAGES
+                .iter()
+                .position(|candidate| *candidate == negotiated.language)?;
+            let entry = CATALOG.iter().find(|entry| entry[0] == message)?;
+            negotiated.translated.store(true, Ordering::Relaxed);
+            Some(entry[column].to_string())
+        })
+        .ok()
+        .flatten()
+        .unwrap_or(message)
+}
+
+/// Picks the supported language the client prefers most from an
+/// `Accept-Language` value such as `fr-CH, fr;q=0.9, en;q=0.8`.
+fn negotiate(accept_language: &str) -> Option<&'static str> {
+    let mut ranges = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let tag = parts.next().filter(|tag| !tag.is_empty())?;
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            Some((tag, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect::<Vec<(&str, f32)>>();
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    ranges.into_iter().find_map(|(tag, _)| {
+        let primary = tag.split('-').next().unwrap_or(tag).to_ascii_lowercase();
+        if primary == "*" {
+            return Some(LANGUAGES[0]);
+        }
+        LANGUAGES
+            .iter()
+            .find(|language| **language == primary)
+            .copied()
+    })
+}
+
+/// Runs the request in the language negotiated from `Accept-Language`, so
+/// `ApiResponse` messages are translated, and reports it in `Content-Language`
+/// on JSON responses whose message is in that language. Messages missing from
+/// the catalog stay in English, so their responses carry no `Content-Language`.
+pub async fn localize_messages(req: Request, next: Next) -> Response {
+    if !*LOCALIZE_MESSAGES {
+        return next.run(req).await;
+    }
+
+    let language = req
+        .headers()
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(negotiate)
+        .unwrap_or(LANGUAGES[0]);
+
+    let translated = Arc::new(AtomicBool::new(false));
+    let negotiated = Negotiated {
+        language,
+        translated: Arc::clone(&translated),
+    };
+    let mut response = LANGUAGE.scope(negotiated, next.run(req)).await;
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    let in_language = language == LANGUAGES[0] || translated.load(Ordering::Relaxed);
+    if is_json && in_language {
+        response
+            .headers_mut()
+            .insert(CONTENT_LANGUAGE, HeaderValue::from_static(language));
+    }
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-language"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_picks_the_preferred_supported_language() {
+        assert_eq!(negotiate("fr-CH, fr;q=0.9, en;q=0.8"), Some("fr"));
+        assert_eq!(negotiate("ja, de;q=0.5, es;q=0.7"), Some("es"));
+        assert_eq!(negotiate("DE"), Some("de"));
+        assert_eq!(negotiate("fr;q=0, en;q=0.1"), Some("en"));
+        assert_eq!(negotiate("*"), Some("en"));
+        assert_eq!(negotiate("ja, zh"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    async fn localize_in(language: &'static str, message: &str) -> (String, bool) {
+        let translated = Arc::new(AtomicBool::new(false));
+        let negotiated = Negotiated {
+            language,
+            translated: Arc::clone(&translated),
+        };
+        let message = LANGUAGE
+            .scope(negotiated, async { localize(message.to_string()) })
+            .await;
+        (message, translated.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn localize_translates_catalog_messages_only() {
+        assert_eq!(
+            localize_in("fr", "Not found").await,
+            ("Introuvable".to_string(), true)
+        );
+        assert_eq!(
+            localize_in("fr", "Client hints").await,
+            ("Client hints".to_string(), false)
+        );
+        assert_eq!(localize("Not found".to_string()), "Not found");
+    }
+}
@@ -0,0 +1,130 @@
+use crate::config::{LOG_EMAIL_MODE, LOG_SAMPLE_RATE, TRUST_PROXY};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Request},
+    http::{Extensions, HeaderMap, StatusCode, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+pub fn init_tracing() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+}
+
+/// Renders an email for log output according to `LOG_EMAIL_MODE`, so raw
+/// addresses only reach the logs when explicitly allowed.
+pub fn log_email(email: &str) -> String {
+    match LOG_EMAIL_MODE.as_str() {
+        "plain" => email.to_string(),
+        "redact" => match email.split_once('@') {
+            Some((local, domain)) => {
+                format!("{}***@{}", local.chars().next().unwrap_or('*'), domain)
+            }
+            None => "***".to_string(),
+        },
+        _ => {
+            let digest = format!("{:x}", Sha256::digest(email.to_lowercase().as_bytes()));
+            format!("sha256:{}", &digest[..16])
+        }
+    }
+}
+
+/// Address of the caller: the last `X-Forwarded-For` hop when `TRUST_PROXY`
+/// is set, otherwise the peer address of the connection. Only the last hop is
+/// appended by the trusted proxy; earlier ones are whatever the client sent.
+pub fn client_ip(headers: &HeaderMap, extensions: &Extensions) -> Option<IpAddr> {
+    let forwarded = headers
+        .get("X-Forwarded-For")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok());
+
+    match forwarded {
+        Some(ip) if *TRUST_PROXY => Some(ip),
+        _ => extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip()),
+    }
+}
+
+/// Extractor flavour of [`client_ip`] for handlers that log the caller.
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl ClientIp {
+    pub fn display(&self) -> String {
+        self.0
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ClientIp(client_ip(&parts.headers, &parts.extensions)))
+    }
+}
+
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Whether a finished request should be logged: every error, and one in
+/// `sample_rate` of the rest.
+fn should_log(status: StatusCode, sequence: u64, sample_rate: u64) -> bool {
+    status.is_client_error()
+        || status.is_server_error()
+        || sequence.is_multiple_of(sample_rate.max(1))
+}
+
+/// Logs the status and latency of requests, sampled by `LOG_SAMPLE_RATE`.
+/// Runs inside the `request_id` span, which carries the method and path.
+pub async fn log_requests(req: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let response = next.run(req).await;
+
+    let status = response.status();
+    let sequence = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    if should_log(status, sequence, *LOG_SAMPLE_RATE) {
+        let latency_ms = started.elapsed().as_millis() as u64;
+        let status = status.as_u16();
+        match status {
+            500.. => error!(status, latency_ms, "request failed"),
+            400.. => warn!(status, latency_ms, "request rejected"),
+            _ => info!(status, latency_ms, "request completed"),
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_log_samples_successes_but_keeps_every_error() {
+        let logged = |status| {
+            (0..10)
+                .filter(|sequence| should_log(status, *sequence, 5))
+                .count()
+        };
+
+        assert_eq!(logged(StatusCode::OK), 2);
+        assert_eq!(logged(StatusCode::NOT_FOUND), 10);
+        assert_eq!(logged(StatusCode::INTERNAL_SERVER_ERROR), 10);
+        assert!((0..3).all(|sequence| should_log(StatusCode::OK, sequence, 0)));
+    }
+}
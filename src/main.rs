@@ -1,36 +1,251 @@
+mod accept;
+mod audit;
 mod config;
-use config::load_dotenv;
-use config::{HOST, MONGO_URI, PORT, SECRET_KEY};
+mod db_queue;
+mod error;
+mod i18n;
+mod logging;
+mod metrics;
+mod query;
+mod rate_limit;
+mod readiness;
+mod request_id;
+#[cfg(test)]
+mod tests;
+mod timezone;
+use accept::require_acceptable;
+use audit::{AuditEntry, FieldChange, diff};
+use config::{
+    ALLOW_MISSING_AGE, CACHE_MAX_AGE, CACHE_STALE_WHILE_REVALIDATE, CORS_ALLOWED_ORIGINS,
+    CORS_EXPOSE_HEADERS, DEFAULT_PAGE_SIZE, EMAIL_VERIFICATION, EMAIL_VERIFICATION_TTL_SECS,
+    ENABLE_ROOT_ROUTE, FORCE_HTTPS, HOST, INDEX_BUILD_BACKGROUND, JSON_CAMEL_CASE,
+    LOGIN_EQUALIZE_TIMING, MAX_BATCH_SIZE, MAX_BODY_BYTES, MAX_LABEL_LENGTH, MAX_LABELS,
+    MAX_PAGE_SIZE, MAX_PAGINATION_OFFSET, MAX_POOL_SIZE, MAX_STATS_DAYS, MIN_POOL_SIZE, MONGO_URI,
+    OPS_AUTH_BASIC, OPS_AUTH_EXEMPT_PRIVATE, OPS_AUTH_TOKEN, PORT, PUBLIC_BASE_URL, SECRET_KEY,
+    SECRET_KEY_PREVIOUS, SKIP_INDEX_CREATION, SSE_BUFFER_CAPACITY, SSE_KEEP_ALIVE_SECS,
+    STRICT_CONTENT_LENGTH, TRUST_PROXY, UNIQUE_NAME_PER_OWNER, USER_RATE_LIMIT,
+    USER_RATE_LIMIT_WINDOW_SECS, WAIT_QUEUE_TIMEOUT_MS, WARM_POOL,
+};
+use config::{effective_config, feature_enabled, is_admin, load_dotenv, route_timeout};
+use db_queue::{DbWaitQueue, db_wait_queue};
+use error::{AppError, classify_db_error, is_duplicate_key, is_missing_index};
+use i18n::{localize, localize_messages};
+use logging::{ClientIp, client_ip, init_tracing, log_email, log_requests};
+use metrics::{METRICS, PoolStats};
+use query::{ALLOWED_SORT_FIELDS, LabelQuery, ListParams, QueryDsl, StrictQuery, to_filter};
+use rate_limit::{RateLimiter, user_rate_limit};
+use readiness::{READINESS, StartupPhase, gate_until_ready, ready};
+use request_id::{current_request_id, request_id};
+use timezone::{display_time, display_timezone, in_current_timezone};
 
 use argon2::{
     Argon2,
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
+    password_hash::{
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+        rand_core::{OsRng, RngCore},
+    },
 };
 use axum::{
     Extension, Json, Router,
-    extract::{Path, Request, State},
-    http::StatusCode,
-    middleware::{Next, from_fn_with_state},
-    response::IntoResponse,
-    routing::{get, post},
+    body::HttpBody,
+    extract::{DefaultBodyLimit, FromRef, FromRequestParts, MatchedPath, Path, Request, State},
+    http::{
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+        header::{
+            AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, IF_NONE_MATCH, LOCATION,
+            TRANSFER_ENCODING, WWW_AUTHENTICATE,
+        },
+        request::Parts,
+    },
+    middleware::{Next, from_fn, from_fn_with_state, map_response},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{any, get, patch, post, put},
 };
-use futures::TryStreamExt;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use futures::{Stream, StreamExt, TryStreamExt, future::try_join_all};
 use jsonwebtoken::{
-    DecodingKey, EncodingKey, Header, Validation, decode, encode, get_current_timestamp,
+    DecodingKey, EncodingKey, Header, TokenData, Validation, decode, encode,
+    errors::ErrorKind as JwtErrorKind, get_current_timestamp,
 };
 use mongodb::{
-    Client, Collection, Database,
-    bson::{doc, oid::ObjectId, to_document},
+    Client, Collection, Database, IndexModel,
+    bson::{Bson, DateTime, Document, doc, oid::ObjectId, to_document},
+    change_stream::event::OperationType,
+    event::EventHandler,
+    options::{ClientOptions, FullDocumentType, IndexOptions, ReturnDocument},
+};
+use once_cell::sync::Lazy;
+use serde::{
+    Deserialize, Serialize, Serializer,
+    ser::{Error as _, SerializeMap},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
+use tokio::{
+    net::TcpListener,
+    sync::{
+        Notify,
+        mpsc::{self, error::TrySendError},
+    },
+};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tracing::{error, info, warn};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Identity {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     id: Option<ObjectId>,
     name: String,
-    age: u8,
+    /// Absent when unknown, which is only accepted if `ALLOW_MISSING_AGE` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    age: Option<u8>,
+    /// Email of the user who created the identity; assigned by the server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner_email: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    labels: Vec<String>,
+    /// Server-assigned timestamps; absent on identities stored before they were tracked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<DateTime>,
+    /// Set when the identity is soft-deleted; such identities are hidden from reads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<DateTime>,
+}
+
+impl Identity {
+    fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            Err("Name must not be empty.".to_string())
+        } else if self.age.is_none() && !*ALLOW_MISSING_AGE {
+            Err("Age is required.".to_string())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Public JSON shape of an identity, kept apart from the stored `Identity` so
+/// the two can evolve independently. Multi-word keys follow `JSON_FIELD_CASE`.
+#[derive(Debug)]
+struct IdentityResponse(Identity);
+
+impl From<Identity> for IdentityResponse {
+    fn from(identity: Identity) -> Self {
+        IdentityResponse(identity)
+    }
+}
+
+impl Serialize for IdentityResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let key = |snake: &'static str, camel: &'static str| {
+            if *JSON_CAMEL_CASE { camel } else { snake }
+        };
+        let identity = &self.0;
+        let timestamp = |at: &DateTime| {
+            display_time(at).ok_or_else(|| S::Error::custom("timestamp out of range"))
+        };
+
+        let mut map = serializer.serialize_map(None)?;
+        if let Some(id) = &identity.id {
+            map.serialize_entry("_id", id)?;
+        }
+        map.serialize_entry("name", &identity.name)?;
+        if let Some(age) = identity.age {
+            map.serialize_entry("age", &age)?;
+        }
+        if let Some(owner_email) = &identity.owner_email {
+            map.serialize_entry(key("owner_email", "ownerEmail"), owner_email)?;
+        }
+        if !identity.labels.is_empty() {
+            map.serialize_entry("labels", &identity.labels)?;
+        }
+        if let Some(created_at) = &identity.created_at {
+            map.serialize_entry(key("created_at", "createdAt"), &timestamp(created_at)?)?;
+        }
+        if let Some(updated_at) = &identity.updated_at {
+            map.serialize_entry(key("updated_at", "updatedAt"), &timestamp(updated_at)?)?;
+        }
+        if let Some(deleted_at) = &identity.deleted_at {
+            map.serialize_entry(key("deleted_at", "deletedAt"), &timestamp(deleted_at)?)?;
+        }
+        map.end()
+    }
+}
+
+/// Public JSON shape of an audit entry, rendered like `IdentityResponse`:
+/// multi-word keys follow `JSON_FIELD_CASE` and `at` uses the display timezone.
+#[derive(Debug)]
+struct AuditEntryResponse(AuditEntry);
+
+impl From<AuditEntry> for AuditEntryResponse {
+    fn from(entry: AuditEntry) -> Self {
+        AuditEntryResponse(entry)
+    }
+}
+
+impl Serialize for AuditEntryResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let entry = &self.0;
+        let at =
+            display_time(&entry.at).ok_or_else(|| S::Error::custom("timestamp out of range"))?;
+
+        let mut map = serializer.serialize_map(None)?;
+        if let Some(id) = &entry.id {
+            map.serialize_entry("_id", id)?;
+        }
+        let identity_id = if *JSON_CAMEL_CASE {
+            "identityId"
+        } else {
+            "identity_id"
+        };
+        map.serialize_entry(identity_id, &entry.identity_id)?;
+        map.serialize_entry("actor", &entry.actor)?;
+        map.serialize_entry("at", &at)?;
+        map.serialize_entry("changes", &entry.changes)?;
+        map.end()
+    }
+}
+
+/// Conflict reported when an owner already has an identity with the same name.
+const DUPLICATE_NAME: &str = "You already have an identity with this name";
+
+/// Drops duplicate labels, keeping first occurrences in order, and enforces
+/// `MAX_LABELS` and `MAX_LABEL_LENGTH`.
+fn check_labels(labels: Vec<String>) -> Result<Vec<String>, String> {
+    let mut unique: Vec<String> = Vec::with_capacity(labels.len());
+    for label in labels {
+        if label.chars().count() > *MAX_LABEL_LENGTH {
+            return Err(format!(
+                "Labels must be at most {} characters long.",
+                *MAX_LABEL_LENGTH
+            ));
+        }
+        if !unique.contains(&label) {
+            unique.push(label);
+        }
+    }
+
+    if unique.len() > *MAX_LABELS {
+        return Err(format!("At most {} labels are allowed.", *MAX_LABELS));
+    }
+    Ok(unique)
+}
+
+#[derive(Debug, Serialize)]
+struct ValidationError {
+    index: usize,
+    error: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,350 +254,1731 @@ struct IdentityUpdate {
     name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     age: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    labels: Option<Vec<String>>,
 }
 
 impl IdentityUpdate {
     fn validate(&self) -> Result<(), String> {
-        if self.age.is_none() && self.name.is_none() {
-            Err("Either age or name must be provided.".to_string())
+        if self.age.is_none() && self.name.is_none() && self.labels.is_none() {
+            Err("At least one of name, age or labels must be provided.".to_string())
+        } else if self
+            .name
+            .as_ref()
+            .is_some_and(|name| name.trim().is_empty())
+        {
+            Err("Name must not be empty.".to_string())
         } else {
             Ok(())
         }
     }
 }
 
+/// Extra listing parameter for `GET /identity`, read alongside `ListParams`.
+#[derive(Debug, Deserialize)]
+struct VisibilityParams {
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteParams {
+    #[serde(default)]
+    hard: bool,
+}
+
 #[derive(Debug, Serialize)]
 struct ApiResponse<T> {
     message: String,
     data: T,
+    /// Number of matching items across all pages, set on paginated listings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<u64>,
+    /// Set on server errors so clients can quote it when reporting a failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl<T> ApiResponse<T> {
+    fn new(message: impl Into<String>, data: T) -> Self {
+        ApiResponse {
+            message: localize(message.into()),
+            data,
+            total: None,
+            request_id: None,
+        }
+    }
+
+    fn with_total(mut self, total: u64) -> Self {
+        self.total = Some(total);
+        self
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Auth {
     email: String,
     password: String,
+    /// Requested new email awaiting confirmation via `/auth/confirm-email`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending_email: Option<String>,
+    /// SHA-256 of the confirmation token sent to `pending_email`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending_email_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending_email_expires_at: Option<DateTime>,
+    /// Must match the `token_version` claim of a token for it to be accepted;
+    /// bumped to revoke every token issued so far. New accounts start at a
+    /// random value, so tokens for an address that changed hands never match.
+    #[serde(default)]
+    token_version: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmailChange {
+    email: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmailConfirmation {
+    token: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
     sub: String,
     exp: u64,
+    /// The account's `token_version` when the token was issued; tokens issued
+    /// before versions existed count as version `0`.
+    #[serde(default)]
+    token_version: i64,
+}
+
+/// Outcome of verifying one token in `POST /auth/verify-batch`.
+#[derive(Debug, Serialize)]
+struct TokenVerification {
+    index: usize,
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    claims: Option<Claims>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+}
+
+/// State shared by the identity routes; handlers extract the collection they need.
+#[derive(Clone)]
+struct IdentityState {
+    identities: Arc<Collection<Identity>>,
+    audit: Arc<Collection<AuditEntry>>,
+}
+
+impl FromRef<IdentityState> for Arc<Collection<Identity>> {
+    fn from_ref(state: &IdentityState) -> Self {
+        Arc::clone(&state.identities)
+    }
+}
+
+impl FromRef<IdentityState> for Arc<Collection<AuditEntry>> {
+    fn from_ref(state: &IdentityState) -> Self {
+        Arc::clone(&state.audit)
+    }
+}
+
+/// State of the account routes, which also move identities between owners.
+#[derive(Clone)]
+struct AccountState {
+    auth: Arc<Collection<Auth>>,
+    identities: Arc<Collection<Identity>>,
+}
+
+impl FromRef<AccountState> for Arc<Collection<Auth>> {
+    fn from_ref(state: &AccountState) -> Self {
+        Arc::clone(&state.auth)
+    }
+}
+
+impl FromRef<AccountState> for Arc<Collection<Identity>> {
+    fn from_ref(state: &AccountState) -> Self {
+        Arc::clone(&state.identities)
+    }
+}
+
+/// Payload of an identity change pushed over `/identity/events`.
+#[derive(Debug, Serialize)]
+struct IdentityChange {
+    operation: OperationType,
+    id: Option<Bson>,
+    document: Option<IdentityResponse>,
+}
+
+/// Inclusive `YYYY-MM-DD` bounds of `GET /identity/created-per-day`, in UTC.
+#[derive(Debug, Deserialize)]
+struct DateRange {
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DayCount {
+    date: String,
+    count: i64,
+}
+
+/// Effective limits reported by `GET /meta` so clients need not hard-code them.
+#[derive(Debug, Serialize)]
+struct ClientHints {
+    default_page_size: u64,
+    max_page_size: u64,
+    max_pagination_offset: u64,
+    allowed_sort_fields: &'static [&'static str],
+    max_body_bytes: usize,
+    max_batch_size: usize,
+    max_labels: usize,
+    max_label_length: usize,
+    user_rate_limit: u32,
+    user_rate_limit_window_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct HealthReport {
+    database: &'static str,
+    ping_ms: u64,
+    pool: PoolStats,
+}
+
+#[derive(Debug, Serialize)]
+struct BuildInfo {
+    name: &'static str,
+    version: &'static str,
+    /// Commit the binary was built from, when `GIT_COMMIT` was set at build time.
+    git_commit: Option<&'static str>,
+    profile: &'static str,
+}
+
+/// Resource usage of the server process; fields are `None` where the
+/// platform does not expose them through `/proc`.
+#[derive(Debug, Serialize)]
+struct ProcessStats {
+    pid: u32,
+    rss_bytes: Option<u64>,
+    threads: Option<u64>,
+    cpu_seconds: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Diagnostics {
+    build: BuildInfo,
+    uptime_secs: u64,
+    process: ProcessStats,
+    health: HealthReport,
+    config: BTreeMap<&'static str, serde_json::Value>,
 }
 
+#[derive(Debug, Serialize)]
+struct ServerTime {
+    utc: String,
+    timezone: &'static str,
+    unix: u64,
+    /// Seconds until the caller's token expires, when a valid one was sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_expires_in: Option<u64>,
+}
+
+/// Failed logins share one response so clients cannot tell unknown emails
+/// from wrong passwords.
+const INVALID_CREDENTIALS: &str = "Invalid email or password";
+
+/// When the server started, for reporting uptime.
+static STARTED_AT: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Hash of a random password, verified against when the email is unknown so
+/// that path costs as much as a real password check.
+static DUMMY_PASSWORD_HASH: Lazy<String> = Lazy::new(|| {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(salt.as_str().as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .unwrap_or_default()
+});
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     load_dotenv();
+    init_tracing();
+    Lazy::force(&STARTED_AT);
+    // Reads every setting, so an invalid value stops startup rather than a request.
+    effective_config();
 
     let db: Database = init_db().await?;
 
     let identity_collection: Arc<Collection<Identity>> = init_identity_collection(&db);
     let auth_collection: Arc<Collection<Auth>> = init_auth_collection(&db);
+    let audit_collection: Arc<Collection<AuditEntry>> = init_audit_collection(&db);
+
+    let app: Router = app(
+        Arc::clone(&identity_collection),
+        Arc::clone(&auth_collection),
+        audit_collection,
+        db.clone(),
+    );
+
+    let listener = TcpListener::bind(format!("{}:{}", *HOST, *PORT)).await?;
+
+    info!("Server up and running on {}", listener.local_addr()?);
+
+    serve(
+        listener,
+        app,
+        &db,
+        &identity_collection,
+        &auth_collection,
+        shutdown_signal(),
+    )
+    .await
+}
+
+/// Serves `app` while the startup tasks run, until `shutdown` resolves or
+/// startup fails. Either way the MongoDB client is closed once the HTTP
+/// connections have drained, and a startup failure is then returned.
+async fn serve(
+    listener: TcpListener,
+    app: Router,
+    db: &Database,
+    identities: &Arc<Collection<Identity>>,
+    auth: &Arc<Collection<Auth>>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let startup_failed = Arc::new(Notify::new());
+    let stop = {
+        let startup_failed = Arc::clone(&startup_failed);
+        async move {
+            tokio::select! {
+                _ = shutdown => {},
+                _ = startup_failed.notified() => {},
+            }
+        }
+    };
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(stop)
+    .into_future();
+
+    // `/ready` reports 503 until these finish; a failure stops the server.
+    let startup = async {
+        let result = run_startup_tasks(db, identities, auth).await;
+        match &result {
+            Ok(()) => {
+                READINESS.set(StartupPhase::Ready);
+                info!("Startup complete, ready for traffic");
+            }
+            Err(e) => {
+                READINESS.set(StartupPhase::Failed);
+                error!("Startup failed, shutting down : {}", e);
+                startup_failed.notify_one();
+            }
+        }
+        result
+    };
 
-    let app: Router = app(identity_collection, auth_collection);
+    let (served, started) = tokio::join!(server, startup);
 
-    let listener =
-        tokio::net::TcpListener::bind(format!("{}:{}", HOST.to_string(), PORT.to_string())).await?;
+    // HTTP connections are drained by now; let in-flight database operations
+    // finish and close the pool instead of dropping its sockets.
+    info!("Closing MongoDB client");
+    db.client().clone().shutdown().await;
+    info!("Shutdown complete");
+    served?;
+    started?;
+    Ok(())
+}
 
-    println!("Server up and running on {}", listener.local_addr()?);
+/// Warms the pool and creates or checks indexes, moving `READINESS` through
+/// each phase. Background index builds do not hold readiness back.
+async fn run_startup_tasks(
+    db: &Database,
+    identities: &Arc<Collection<Identity>>,
+    auth: &Arc<Collection<Auth>>,
+) -> Result<(), mongodb::error::Error> {
+    if *WARM_POOL {
+        READINESS.set(StartupPhase::WarmingPool);
+        warm_pool(db).await?;
+    }
 
-    axum::serve(listener, app).await?;
+    READINESS.set(StartupPhase::CheckingIndexes);
+    if *SKIP_INDEX_CREATION {
+        info!("Skipping index creation");
+        match has_unique_email_index(auth).await {
+            Ok(true) => {}
+            Ok(false) => warn!(
+                "No unique index on auth.email; concurrent signups for the same email can both succeed"
+            ),
+            Err(e) => warn!("Could not check the auth.email index : {}", e),
+        }
+    } else if *INDEX_BUILD_BACKGROUND {
+        let identities = Arc::clone(identities);
+        let auth = Arc::clone(auth);
+        tokio::spawn(async move {
+            match ensure_indexes(&identities, &auth).await {
+                Ok(()) => info!("Indexes are in place"),
+                Err(e) => error!("Failed to create indexes : {}", e),
+            }
+        });
+    } else {
+        ensure_indexes(identities, auth).await?;
+    }
     Ok(())
 }
 
+/// Resolves on Ctrl+C or, on Unix, `SIGTERM`, after which the server stops
+/// accepting connections and waits for open ones to finish.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl+C : {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM : {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutdown signal received, draining connections");
+}
+
 fn app(
     identity_collection: Arc<Collection<Identity>>,
     auth_collection: Arc<Collection<Auth>>,
+    audit_collection: Arc<Collection<AuditEntry>>,
+    database: Database,
 ) -> Router {
-    let crud_router = crud_router(Arc::clone(&identity_collection)).route_layer(
-        from_fn_with_state(Arc::clone(&auth_collection), login_required),
-    );
-    let auth_router = auth_router(Arc::clone(&auth_collection));
+    let user_rate_limiter = Arc::new(RateLimiter::new(
+        *USER_RATE_LIMIT,
+        Duration::from_secs(*USER_RATE_LIMIT_WINDOW_SECS),
+    ));
+
+    let db_wait_queue_state = Arc::new(DbWaitQueue::new(
+        *MAX_POOL_SIZE,
+        Duration::from_millis(*WAIT_QUEUE_TIMEOUT_MS),
+    ));
+
+    let protected_router = crud_router(IdentityState {
+        identities: Arc::clone(&identity_collection),
+        audit: Arc::clone(&audit_collection),
+    })
+    .merge(admin_router(database.clone()))
+    .merge(account_router(AccountState {
+        auth: Arc::clone(&auth_collection),
+        identities: Arc::clone(&identity_collection),
+    }))
+    .route("/protected", get(protected))
+    .route_layer(from_fn_with_state(
+        Arc::clone(&user_rate_limiter),
+        user_rate_limit,
+    ))
+    .route_layer(from_fn_with_state(
+        Arc::clone(&auth_collection),
+        login_required,
+    ))
+    .route_layer(from_fn_with_state(
+        Arc::clone(&db_wait_queue_state),
+        db_wait_queue,
+    ))
+    .route_layer(from_fn(gate_until_ready));
+    let auth_router = auth_router(AccountState {
+        auth: Arc::clone(&auth_collection),
+        identities: Arc::clone(&identity_collection),
+    })
+    .route_layer(from_fn_with_state(db_wait_queue_state, db_wait_queue))
+    .route_layer(from_fn(gate_until_ready));
+    let mut public_router = Router::new();
+    if *ENABLE_ROOT_ROUTE {
+        public_router = public_router.route("/", get(|| async { "Hello World" }));
+    }
+    let public_router = public_router.route("/meta", get(meta));
+    let public_router = public_router.layer(map_response(public_cache_control));
 
     Router::new()
-        .route("/", get(|| async { "Hello World" }))
-        .route("/protected", get(protected))
-        .route_layer(from_fn_with_state(
-            Arc::clone(&auth_collection),
-            login_required,
-        ))
-        .merge(crud_router)
+        .merge(protected_router)
+        .merge(ops_router(database))
         .merge(auth_router)
+        .layer(map_response(no_store))
+        .merge(public_router)
+        .merge(disabled_feature_router())
+        .fallback(not_found)
+        .layer(DefaultBodyLimit::max(*MAX_BODY_BYTES))
+        .layer(from_fn(request_timeout))
+        .layer(from_fn(require_acceptable))
+        .layer(from_fn(require_content_length))
+        .layer(from_fn(enforce_https))
+        .layer(from_fn(display_timezone))
+        .layer(from_fn(localize_messages))
+        .layer(from_fn(log_requests))
+        .layer(from_fn(request_id))
+        .layer(cors_layer())
+}
+
+/// CORS policy for browser clients. Custom headers such as `X-Total-Count` and
+/// `X-Request-Id` are only readable by scripts when listed in `CORS_EXPOSE_HEADERS`.
+fn cors_layer() -> CorsLayer {
+    let allow_origin = if CORS_ALLOWED_ORIGINS.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            CORS_ALLOWED_ORIGINS
+                .iter()
+                .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+        )
+    };
+
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(Any)
+        .allow_headers(Any)
+        .expose_headers(
+            CORS_EXPOSE_HEADERS
+                .iter()
+                .filter_map(|header| HeaderName::from_bytes(header.as_bytes()).ok())
+                .collect::<Vec<HeaderName>>(),
+        )
+}
+
+/// Behind a trusted TLS-terminating proxy, redirects plain-HTTP reads to their
+/// `https` URL on the `PUBLIC_BASE_URL` host and refuses plain-HTTP writes with `426 Upgrade Required`.
+async fn enforce_https(req: Request, next: Next) -> Response {
+    if !*FORCE_HTTPS || !*TRUST_PROXY {
+        return next.run(req).await;
+    }
+
+    let is_plain_http = req
+        .headers()
+        .get("X-Forwarded-Proto")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("http"));
+    if !is_plain_http {
+        return next.run(req).await;
+    }
+
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        let response_data = ApiResponse::new("HTTPS is required", ());
+        return (StatusCode::UPGRADE_REQUIRED, Json(response_data)).into_response();
+    }
+
+    // The redirect target comes from configuration, never from the client's
+    // `Host` or `X-Forwarded-Host`, so it cannot point anywhere else.
+    let authority = PUBLIC_BASE_URL
+        .split_once("://")
+        .map_or(PUBLIC_BASE_URL.as_str(), |(_, rest)| rest);
+    let authority = authority.split('/').next().unwrap_or(authority);
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+
+    match HeaderValue::from_str(&format!("https://{}{}", authority, path)) {
+        Ok(location) => (StatusCode::MOVED_PERMANENTLY, [(LOCATION, location)]).into_response(),
+        Err(_) => AppError::BadRequest("HTTPS is required".to_string()).into_response(),
+    }
+}
+
+/// In `STRICT_CONTENT_LENGTH` mode, answers `411 Length Required` to bodies
+/// of unknown length: chunked uploads, and `POST`, `PUT` or `PATCH` bodies
+/// streamed without a `Content-Length`, as HTTP/2 and HTTP/3 clients do.
+/// Requests known to have no body need neither header.
+async fn require_content_length(req: Request, next: Next) -> Response {
+    let headers = req.headers();
+    let has_body = headers.contains_key(TRANSFER_ENCODING)
+        || (matches!(*req.method(), Method::POST | Method::PUT | Method::PATCH)
+            && req.body().size_hint().exact() != Some(0));
+    if *STRICT_CONTENT_LENGTH && has_body && !headers.contains_key(CONTENT_LENGTH) {
+        let response_data = ApiResponse::new("Content-Length is required", ());
+        return (StatusCode::LENGTH_REQUIRED, Json(response_data)).into_response();
+    }
+
+    next.run(req).await
+}
+
+/// Answers `504 Gateway Timeout` when a handler exceeds its route's budget
+/// (see `ROUTE_TIMEOUTS`). Streaming bodies are not limited once they start.
+async fn request_timeout(matched_path: Option<MatchedPath>, req: Request, next: Next) -> Response {
+    let limit = route_timeout(matched_path.as_ref().map(MatchedPath::as_str));
+    match tokio::time::timeout(limit, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("Request timed out after {:?}", limit);
+            let mut response_data = ApiResponse::new("Request timed out", ());
+            response_data.request_id = current_request_id();
+            (StatusCode::GATEWAY_TIMEOUT, Json(response_data)).into_response()
+        }
+    }
+}
+
+async fn not_found() -> impl IntoResponse {
+    let response_data = ApiResponse::new("Not found", ());
+    (StatusCode::NOT_FOUND, Json(response_data))
+}
+
+/// Lets browsers and CDNs cache successful public responses, serving a stale
+/// copy while they revalidate in the background.
+async fn public_cache_control(mut response: Response) -> Response {
+    if response.status().is_success() {
+        let directive = format!(
+            "public, max-age={}, stale-while-revalidate={}",
+            *CACHE_MAX_AGE, *CACHE_STALE_WHILE_REVALIDATE
+        );
+        if let Ok(value) = HeaderValue::from_str(&directive) {
+            response.headers_mut().insert(CACHE_CONTROL, value);
+        }
+    }
+    response
+}
+
+/// Keeps authenticated and per-user responses out of every cache.
+async fn no_store(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
 }
 
 async fn init_db() -> Result<Database, Box<dyn std::error::Error>> {
-    let client: Client = Client::with_uri_str(MONGO_URI.to_string()).await?;
+    let client: Client = Client::with_options(client_options(MONGO_URI.as_str()).await?)?;
     let database = client.database("restful_axum");
     database.run_command(doc! { "ping" : 1 }).await?;
 
     Ok(database)
 }
 
+/// Options for a client of `uri`, with the configured pool size and the
+/// driver event handlers that feed `/metrics`.
+async fn client_options(uri: &str) -> Result<ClientOptions, mongodb::error::Error> {
+    let mut client_options = ClientOptions::parse(uri).await?;
+    if *MIN_POOL_SIZE > 0 {
+        client_options.min_pool_size = Some(*MIN_POOL_SIZE);
+    }
+    client_options.max_pool_size = Some(*MAX_POOL_SIZE);
+    client_options.cmap_event_handler = Some(EventHandler::callback(|event| {
+        METRICS.record_cmap_event(&event)
+    }));
+    client_options.command_event_handler = Some(EventHandler::callback(|event| {
+        METRICS.record_command_event(&event)
+    }));
+    Ok(client_options)
+}
+
+/// Issues `MIN_POOL_SIZE` concurrent pings so the pool holds open connections
+/// before the server starts accepting traffic.
+async fn warm_pool(database: &Database) -> Result<(), mongodb::error::Error> {
+    let connections = (*MIN_POOL_SIZE).max(1);
+    let pings = (0..connections).map(|_| database.run_command(doc! { "ping": 1 }).into_future());
+    try_join_all(pings).await?;
+
+    info!("Warmed up {} MongoDB connection(s)", connections);
+    Ok(())
+}
+
 fn init_identity_collection(database: &Database) -> Arc<Collection<Identity>> {
     Arc::new(database.collection::<Identity>("identity"))
 }
 
+/// Creates the indexes the application relies on; existing indexes with the
+/// same definition are left untouched.
+async fn ensure_indexes(
+    identities: &Collection<Identity>,
+    auth: &Collection<Auth>,
+) -> Result<(), mongodb::error::Error> {
+    auth.create_index(
+        IndexModel::builder()
+            .keys(doc! { "email": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+    )
+    .await?;
+    auth.create_index(
+        IndexModel::builder()
+            .keys(doc! { "pending_email_token": 1 })
+            .options(IndexOptions::builder().sparse(true).build())
+            .build(),
+    )
+    .await?;
+
+    if *UNIQUE_NAME_PER_OWNER {
+        // Replaced by `owner_email_name_deleted_at_unique`; left in place it
+        // would keep soft-deleted identities holding their names.
+        match identities.drop_index("owner_email_name_unique").await {
+            Ok(()) => info!("Dropped superseded index owner_email_name_unique"),
+            Err(e) if is_missing_index(&e) => {}
+            Err(e) => return Err(e),
+        }
+
+        // Identities created before ownership was recorded are left out, so
+        // their names need not be unique. Live identities all lack
+        // `deleted_at`, so only they compete for a name; soft-deleted ones are
+        // told apart by their deletion time.
+        let index = IndexModel::builder()
+            .keys(doc! { "owner_email": 1, "name": 1, "deleted_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name("owner_email_name_deleted_at_unique".to_string())
+                    .unique(true)
+                    .partial_filter_expression(doc! { "owner_email": { "$exists": true } })
+                    .build(),
+            )
+            .build();
+        identities.create_index(index).await?;
+    }
+    Ok(())
+}
+
+/// Whether `auth` has the unique `email` index that `signup` relies on to
+/// reject duplicate accounts, including ones created concurrently.
+async fn has_unique_email_index(auth: &Collection<Auth>) -> Result<bool, mongodb::error::Error> {
+    let indexes = auth
+        .list_indexes()
+        .await?
+        .try_collect::<Vec<IndexModel>>()
+        .await?;
+    Ok(indexes.iter().any(|index| {
+        index.keys == doc! { "email": 1 }
+            && index
+                .options
+                .as_ref()
+                .and_then(|options| options.unique)
+                .unwrap_or(false)
+    }))
+}
+
 fn init_auth_collection(database: &Database) -> Arc<Collection<Auth>> {
     Arc::new(database.collection::<Auth>("auth"))
 }
 
-fn crud_router(collection: Arc<Collection<Identity>>) -> Router {
-    Router::new()
+fn init_audit_collection(database: &Database) -> Arc<Collection<AuditEntry>> {
+    Arc::new(database.collection::<AuditEntry>("identity_audit"))
+}
+
+fn crud_router(state: IdentityState) -> Router {
+    let mut router = Router::new()
         .route("/identity", post(create_identity).get(get_all_identities))
+        .route("/me/identities", get(get_my_identities))
         .route(
             "/identity/{id}",
             get(get_identity)
+                .put(put_identity)
                 .patch(update_identity)
                 .delete(delete_identity),
         )
-        .with_state(Arc::clone(&collection))
+        .route("/identity/{id}/labels", put(replace_labels))
+        .route(
+            "/identity/{id}/labels/{label}",
+            post(add_label).delete(remove_label),
+        )
+        .route("/identity/{id}/audit", get(get_identity_audit))
+        .route("/identity/created-per-day", get(created_per_day));
+    if feature_enabled("validate") {
+        router = router.route("/identity/validate", post(validate_identities));
+    }
+    if feature_enabled("query") {
+        router = router
+            .route("/identity/query", post(query_identities))
+            .route("/identity/by-labels", post(identities_by_labels));
+    }
+    if feature_enabled("events") {
+        router = router.route("/identity/events", get(identity_events));
+    }
+
+    router.with_state(state)
+}
+
+/// Answers `404` on the paths of disabled `/identity/...` features, as for any
+/// unknown path, instead of letting `/identity/{id}` match them.
+fn disabled_feature_router() -> Router {
+    let mut router = Router::new();
+    for (feature, paths) in [
+        ("validate", &["/identity/validate"][..]),
+        ("query", &["/identity/query", "/identity/by-labels"]),
+        ("events", &["/identity/events"]),
+    ] {
+        if !feature_enabled(feature) {
+            for path in paths {
+                router = router.route(path, any(not_found));
+            }
+        }
+    }
+    router
+}
+
+/// Admin-only routes; callers must also pass `login_required`.
+fn admin_router(database: Database) -> Router {
+    let mut router = Router::new();
+    if feature_enabled("db_stats") {
+        router = router.route(
+            "/admin/db-stats",
+            get(db_stats).route_layer(from_fn(admin_required)),
+        );
+    }
+    if feature_enabled("diagnostics") {
+        router = router.route(
+            "/admin/diagnostics",
+            get(diagnostics).route_layer(from_fn(admin_required)),
+        );
+    }
+    router
+        .route(
+            "/auth/verify-batch",
+            post(verify_token_batch).route_layer(from_fn(admin_required)),
+        )
+        .with_state(database)
+}
+
+/// Unauthenticated operational routes. `/ready` is always registered, the
+/// others behind their feature flag.
+fn ops_router(database: Database) -> Router {
+    let mut router = Router::new().route("/ready", get(ready));
+    if feature_enabled("time") {
+        router = router.route("/time", get(server_time));
+    }
+    if feature_enabled("metrics") {
+        router = router.route("/metrics", get(metrics).route_layer(from_fn(ops_auth)));
+    }
+    if feature_enabled("health") {
+        router = router.route(
+            "/health/detailed",
+            get(health_detailed).route_layer(from_fn(ops_auth)),
+        );
+    }
+    router.with_state(database)
 }
 
-fn auth_router(collection: Arc<Collection<Auth>>) -> Router {
+fn auth_router(state: AccountState) -> Router {
     Router::new()
         .route("/signup", post(signup))
         .route("/login", post(login))
-        .with_state(Arc::clone(&collection))
+        .route("/auth/confirm-email", get(confirm_email))
+        .with_state(state)
+}
+
+/// Routes acting on the authenticated user's own account; callers must also pass `login_required`.
+fn account_router(state: AccountState) -> Router {
+    Router::new()
+        .route("/me/email", patch(change_email))
+        .with_state(state)
 }
 
 async fn create_identity(
     State(id_collection): State<Arc<Collection<Identity>>>,
+    Extension(email): Extension<String>,
+    Json(identity): Json<Identity>,
+) -> impl IntoResponse {
+    if let Err(e) = identity.validate() {
+        return AppError::BadRequest(e).into_response();
+    }
+    let labels = match check_labels(identity.labels) {
+        Ok(labels) => labels,
+        Err(e) => return AppError::Unprocessable(e).into_response(),
+    };
+
+    let now = DateTime::now();
+    let result = id_collection
+        .insert_one(Identity {
+            id: None,
+            name: identity.name,
+            age: identity.age,
+            owner_email: Some(email),
+            labels,
+            created_at: Some(now),
+            updated_at: Some(now),
+            deleted_at: None,
+        })
+        .await;
+
+    match result {
+        Ok(result) => {
+            let response_data = ApiResponse::new("Identity created", result.inserted_id);
+            (StatusCode::CREATED, Json(response_data)).into_response()
+        }
+        Err(e) if is_duplicate_key(&e) => {
+            AppError::Conflict(DUPLICATE_NAME.to_string()).into_response()
+        }
+        Err(e) => classify_db_error(&e).into_response(),
+    }
+}
+
+/// Creates or fully replaces the identity at a client-chosen id. With
+/// `If-None-Match: *` it only creates, answering `412 Precondition Failed` when
+/// the id is already taken, so retried creates cannot produce duplicates.
+async fn put_identity(
+    State(collection): State<Arc<Collection<Identity>>>,
+    State(audit): State<Arc<Collection<AuditEntry>>>,
+    Extension(email): Extension<String>,
+    Path(id): Path<ObjectId>,
+    headers: HeaderMap,
     Json(identity): Json<Identity>,
 ) -> impl IntoResponse {
-    let result = id_collection
-        .insert_one(Identity {
-            id: None,
-            name: identity.name,
-            age: identity.age,
-        })
-        .await;
+    let create_only = match headers.get(IF_NONE_MATCH).map(HeaderValue::as_bytes) {
+        None => false,
+        Some(b"*") => true,
+        Some(_) => {
+            return AppError::BadRequest("Only 'If-None-Match: *' is supported".to_string())
+                .into_response();
+        }
+    };
+    if let Err(e) = identity.validate() {
+        return AppError::BadRequest(e).into_response();
+    }
+    let labels = match check_labels(identity.labels) {
+        Ok(labels) => labels,
+        Err(e) => return AppError::Unprocessable(e).into_response(),
+    };
+
+    let now = DateTime::now();
+    if create_only {
+        let result = collection
+            .insert_one(Identity {
+                id: Some(id),
+                name: identity.name,
+                age: identity.age,
+                owner_email: Some(email),
+                labels,
+                created_at: Some(now),
+                updated_at: Some(now),
+                deleted_at: None,
+            })
+            .await;
+        return match result {
+            Ok(_) => identity_created(id),
+            Err(e) if is_duplicate_key(&e) => duplicate_on_put(&collection, id, true).await,
+            Err(e) => classify_db_error(&e).into_response(),
+        };
+    }
+
+    let mut set = doc! { "name": identity.name, "labels": labels, "updated_at": now };
+    let mut update = doc! { "$setOnInsert": { "owner_email": &email, "created_at": now } };
+    match identity.age {
+        Some(age) => {
+            set.insert("age", i32::from(age));
+        }
+        None => {
+            update.insert("$unset", doc! { "age": "" });
+        }
+    }
+    update.insert("$set", set.clone());
+    set.remove("updated_at");
+
+    let result = collection
+        .clone_with_type::<Document>()
+        .find_one_and_update(not_deleted(doc! { "_id": id }), update)
+        .upsert(true)
+        .return_document(ReturnDocument::Before)
+        .await;
+
+    let before = match result {
+        Ok(Some(before)) => before,
+        Ok(None) => return identity_created(id),
+        Err(e) if is_duplicate_key(&e) => return duplicate_on_put(&collection, id, false).await,
+        Err(e) => return classify_db_error(&e).into_response(),
+    };
+
+    let mut changes = diff(&before, &set);
+    if identity.age.is_none() && before.contains_key("age") {
+        let change = FieldChange {
+            before: before.get("age").cloned(),
+            after: None,
+        };
+        changes.insert("age".to_string(), change);
+    }
+    if changes.is_empty() {
+        let response_data = ApiResponse::new("No changes made", ());
+        return (StatusCode::OK, Json(response_data)).into_response();
+    }
+
+    let entry = AuditEntry {
+        id: None,
+        identity_id: id,
+        actor: email,
+        at: DateTime::now(),
+        changes,
+    };
+    if let Err(e) = audit.insert_one(entry).await {
+        error!("Failed to record audit entry for identity {} : {}", id, e);
+    }
+
+    let response_data = ApiResponse::new("Updated", ());
+    (StatusCode::OK, Json(response_data)).into_response()
+}
+
+fn identity_created(id: ObjectId) -> Response {
+    let response_data = ApiResponse::new("Identity created", id);
+    (StatusCode::CREATED, Json(response_data)).into_response()
+}
+
+/// Tells apart the two unique indexes a `PUT` can hit: a taken id or a name
+/// the owner already uses (`409`). A taken id fails the `If-None-Match: *`
+/// precondition (`412`); without one, a soft-deleted identity at the id is not
+/// brought back (`409`), and a live one was being renamed into a duplicate.
+async fn duplicate_on_put(
+    collection: &Collection<Identity>,
+    id: ObjectId,
+    create_only: bool,
+) -> Response {
+    match collection.find_one(doc! { "_id": id }).await {
+        Ok(Some(_)) if create_only => {
+            let response_data = ApiResponse::new("An identity with this id already exists", ());
+            (StatusCode::PRECONDITION_FAILED, Json(response_data)).into_response()
+        }
+        Ok(Some(identity)) if identity.deleted_at.is_some() => {
+            AppError::Conflict("An identity with this id was deleted".to_string()).into_response()
+        }
+        // The id is free or live, so the write hit the owner's unique name.
+        Ok(_) => AppError::Conflict(DUPLICATE_NAME.to_string()).into_response(),
+        Err(e) => classify_db_error(&e).into_response(),
+    }
+}
+
+async fn validate_identities(Json(items): Json<Vec<serde_json::Value>>) -> impl IntoResponse {
+    if items.len() > *MAX_BATCH_SIZE {
+        let response_data = ApiResponse::new(
+            format!("Batch exceeds the maximum size of {}", *MAX_BATCH_SIZE),
+            (),
+        );
+        return (StatusCode::PAYLOAD_TOO_LARGE, Json(response_data)).into_response();
+    }
+
+    let errors = items
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, item)| {
+            serde_json::from_value::<Identity>(item)
+                .map_err(|e| e.to_string())
+                .and_then(|identity| {
+                    identity.validate()?;
+                    check_labels(identity.labels).map(drop)
+                })
+                .err()
+                .map(|error| ValidationError { index, error })
+        })
+        .collect::<Vec<ValidationError>>();
+
+    let message = if errors.is_empty() {
+        "All identities are valid"
+    } else {
+        "Some identities are invalid"
+    };
+    let response_data = ApiResponse::new(message, errors);
+    (StatusCode::OK, Json(response_data)).into_response()
+}
+
+/// Lists identities. Admins may pass `?include_deleted=true` to also see
+/// soft-deleted identities, which then carry their `deleted_at`.
+async fn get_all_identities(
+    State(collection): State<Arc<Collection<Identity>>>,
+    Extension(email): Extension<String>,
+    StrictQuery(params): StrictQuery<ListParams>,
+    StrictQuery(visibility): StrictQuery<VisibilityParams>,
+) -> impl IntoResponse {
+    let filter = if visibility.include_deleted {
+        if !is_admin(&email) {
+            return AppError::Forbidden("include_deleted requires admin access".to_string())
+                .into_response();
+        }
+        doc! {}
+    } else {
+        not_deleted(doc! {})
+    };
+
+    identity_page(&collection, filter, &params, "Fetched all identities").await
+}
+
+/// Lists the identities created by the authenticated user.
+async fn get_my_identities(
+    State(collection): State<Arc<Collection<Identity>>>,
+    Extension(email): Extension<String>,
+    StrictQuery(params): StrictQuery<ListParams>,
+) -> impl IntoResponse {
+    identity_page(
+        &collection,
+        not_deleted(doc! { "owner_email": email }),
+        &params,
+        "Fetched your identities",
+    )
+    .await
+}
+
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+/// Parses a `YYYY-MM-DD` date as midnight UTC.
+fn parse_day(name: &str, day: &str) -> Result<DateTime, String> {
+    DateTime::parse_rfc3339_str(format!("{}T00:00:00Z", day))
+        .map_err(|_| format!("'{}' must be a date formatted as YYYY-MM-DD", name))
+}
+
+/// Counts the identities created on each day between `from` and `to`
+/// (inclusive, UTC), including days on which none were created.
+async fn created_per_day(
+    State(collection): State<Arc<Collection<Identity>>>,
+    StrictQuery(range): StrictQuery<DateRange>,
+) -> impl IntoResponse {
+    let (from, to) = match (parse_day("from", &range.from), parse_day("to", &range.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => return AppError::BadRequest(e).into_response(),
+    };
+    let days = (to.timestamp_millis() - from.timestamp_millis()) / DAY_MILLIS + 1;
+    if days < 1 {
+        return AppError::BadRequest("'from' must not be after 'to'".to_string()).into_response();
+    }
+    if days > *MAX_STATS_DAYS {
+        return AppError::BadRequest(format!(
+            "The range must not span more than {} days",
+            *MAX_STATS_DAYS
+        ))
+        .into_response();
+    }
+    let end = DateTime::from_millis(to.timestamp_millis() + DAY_MILLIS);
+
+    let pipeline = vec![
+        doc! { "$match": not_deleted(doc! { "created_at": { "$gte": from, "$lt": end } }) },
+        doc! { "$group": {
+            "_id": { "$dateToString": { "format": "%Y-%m-%d", "date": "$created_at" } },
+            "count": { "$sum": 1 },
+        } },
+        doc! { "$project": { "_id": 0, "date": "$_id", "count": 1 } },
+    ];
+    let result = collection.aggregate(pipeline).with_type::<DayCount>().await;
+    let counts = match result {
+        Ok(cursor) => match cursor.try_collect::<Vec<DayCount>>().await {
+            Ok(counts) => counts,
+            Err(e) => return classify_db_error(&e).into_response(),
+        },
+        Err(e) => return classify_db_error(&e).into_response(),
+    };
+
+    let mut buckets = Vec::with_capacity(days as usize);
+    for day in 0..days {
+        let start = DateTime::from_millis(from.timestamp_millis() + day * DAY_MILLIS);
+        let date = match start.try_to_rfc3339_string() {
+            Ok(timestamp) => timestamp[..10].to_string(),
+            Err(e) => return AppError::internal(e).into_response(),
+        };
+        let count = counts
+            .iter()
+            .find(|bucket| bucket.date == date)
+            .map_or(0, |bucket| bucket.count);
+        buckets.push(DayCount { date, count });
+    }
+
+    let response_data = ApiResponse::new("Identities created per day", buckets);
+    (StatusCode::OK, Json(response_data)).into_response()
+}
+
+async fn query_identities(
+    State(collection): State<Arc<Collection<Identity>>>,
+    StrictQuery(params): StrictQuery<ListParams>,
+    Json(query): Json<QueryDsl>,
+) -> impl IntoResponse {
+    match to_filter(&query) {
+        Ok(filter) => identity_page(
+            &collection,
+            not_deleted(filter),
+            &params,
+            "Fetched identities",
+        )
+        .await
+        .into_response(),
+        Err(e) => AppError::BadRequest(e).into_response(),
+    }
+}
+
+async fn identities_by_labels(
+    State(collection): State<Arc<Collection<Identity>>>,
+    StrictQuery(params): StrictQuery<ListParams>,
+    Json(query): Json<LabelQuery>,
+) -> impl IntoResponse {
+    match query.to_filter() {
+        Ok(filter) => identity_page(
+            &collection,
+            not_deleted(filter),
+            &params,
+            "Fetched identities",
+        )
+        .await
+        .into_response(),
+        Err(e) => AppError::BadRequest(e).into_response(),
+    }
+}
+
+/// Fetches one page of identities matching `filter`, reporting the overall
+/// match count in `X-Total-Count` and `total`. No matches is a `200` with an
+/// empty `data`; a failed query is always an error response, never an empty page.
+async fn identity_page(
+    collection: &Collection<Identity>,
+    filter: Document,
+    params: &ListParams,
+    message: &str,
+) -> Response {
+    if let Err(e) = params.validate() {
+        return AppError::BadRequest(e).into_response();
+    }
+    let sort = match params.sort() {
+        Ok(sort) => sort,
+        Err(e) => return AppError::BadRequest(e).into_response(),
+    };
+
+    let total = match collection.count_documents(filter.clone()).await {
+        Ok(total) => total,
+        Err(e) => return classify_db_error(&e).into_response(),
+    };
+
+    let result = collection
+        .find(params.apply_cursor(filter))
+        .sort(sort)
+        .skip(params.skip())
+        .limit(params.per_page() as i64)
+        .await;
+
+    match result {
+        Ok(cursor) => match cursor.try_collect::<Vec<Identity>>().await {
+            Ok(result) => {
+                let result: Vec<IdentityResponse> = result.into_iter().map(Into::into).collect();
+                let response_data = ApiResponse::new(message, result).with_total(total);
+
+                (
+                    StatusCode::OK,
+                    [("X-Total-Count", total.to_string())],
+                    Json(response_data),
+                )
+                    .into_response()
+            }
+            Err(e) => classify_db_error(&e).into_response(),
+        },
+        Err(e) => classify_db_error(&e).into_response(),
+    }
+}
+
+/// Streams identity changes as server-sent events, sending keep-alive comments
+/// every `SSE_KEEP_ALIVE_SECS` while idle so clients and proxies can spot dead
+/// connections. Requires MongoDB to run as a replica set.
+///
+/// Changes are buffered per client, up to `SSE_BUFFER_CAPACITY` events. When a
+/// slow client lets the buffer fill, further changes are dropped and the next
+/// event it receives is a `lagged` event counting them, so memory stays bounded.
+async fn identity_events(State(collection): State<Arc<Collection<Identity>>>) -> Response {
+    let change_stream = match collection
+        .watch()
+        .full_document(FullDocumentType::UpdateLookup)
+        .await
+    {
+        Ok(change_stream) => change_stream,
+        Err(e) => return classify_db_error(&e).into_response(),
+    };
+
+    let changes = change_stream.map(|change| {
+        change.map(|change| IdentityChange {
+            operation: change.operation_type,
+            id: change.document_key.and_then(|key| key.get("_id").cloned()),
+            document: change.full_document.map(IdentityResponse::from),
+        })
+    });
+    change_events(
+        changes,
+        *SSE_BUFFER_CAPACITY,
+        Duration::from_secs(*SSE_KEEP_ALIVE_SECS),
+    )
+}
+
+/// Relays `changes` to the client through a buffer of `capacity` events, with
+/// a keep-alive comment every `keep_alive` while idle. The stream ends after
+/// the first error.
+fn change_events<S>(mut changes: S, capacity: usize, keep_alive: Duration) -> Response
+where
+    S: Stream<Item = Result<IdentityChange, mongodb::error::Error>> + Send + Unpin + 'static,
+{
+    let (sender, receiver) = mpsc::channel::<Result<Event, axum::Error>>(capacity);
+    tokio::spawn(in_current_timezone(async move {
+        let mut dropped: u64 = 0;
+        loop {
+            // Stop watching as soon as the client goes away, even if no change arrives.
+            let change = tokio::select! {
+                change = changes.next() => change,
+                _ = sender.closed() => return,
+            };
+            let Some(change) = change else { return };
+
+            let event = change
+                .map_err(axum::Error::new)
+                .and_then(|change| Event::default().event("identity").json_data(change));
+            let failed = event.is_err();
+
+            if dropped > 0 {
+                let lagged = Event::default()
+                    .event("lagged")
+                    .json_data(doc! { "dropped": dropped as i64 });
+                match sender.try_send(lagged) {
+                    Ok(()) => dropped = 0,
+                    Err(TrySendError::Full(_)) => {
+                        dropped += 1;
+                        continue;
+                    }
+                    Err(TrySendError::Closed(_)) => return,
+                }
+            }
+            match sender.try_send(event) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => dropped += 1,
+                Err(TrySendError::Closed(_)) => return,
+            }
+            if failed {
+                return;
+            }
+        }
+    }));
+
+    let events = futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|event| (event, receiver))
+    });
+
+    Sse::new(events)
+        .keep_alive(KeepAlive::new().interval(keep_alive).text("keep-alive"))
+        .into_response()
+}
+
+async fn get_identity(
+    State(collection): State<Arc<Collection<Identity>>>,
+    Path(id): Path<ObjectId>,
+) -> impl IntoResponse {
+    let result = collection.find_one(not_deleted(doc! { "_id": id })).await;
+
+    match result {
+        Ok(Some(identity)) => {
+            let response_data = ApiResponse::new("Fetched", IdentityResponse::from(identity));
+
+            (StatusCode::OK, Json(response_data)).into_response()
+        }
+        Ok(None) => AppError::NotFound("Identity does not exist".to_string()).into_response(),
+        Err(e) => classify_db_error(&e).into_response(),
+    }
+}
+
+/// Applies a partial update and records the changed fields, with their old
+/// and new values, in the identity's audit history.
+async fn update_identity(
+    State(collection): State<Arc<Collection<Identity>>>,
+    State(audit): State<Arc<Collection<AuditEntry>>>,
+    Extension(email): Extension<String>,
+    Path(id): Path<ObjectId>,
+    Json(mut id_data): Json<IdentityUpdate>,
+) -> impl IntoResponse {
+    if let Err(e) = id_data.validate() {
+        return AppError::BadRequest(e).into_response();
+    }
+    if let Some(labels) = id_data.labels.take() {
+        match check_labels(labels) {
+            Ok(labels) => id_data.labels = Some(labels),
+            Err(e) => return AppError::Unprocessable(e).into_response(),
+        }
+    }
+
+    let filter = not_deleted(doc! { "_id": id });
+
+    let update_data = match to_document(&id_data) {
+        Ok(document) => document,
+        Err(e) => return AppError::internal(e).into_response(),
+    };
+
+    let mut set = update_data.clone();
+    set.insert("updated_at", DateTime::now());
+    let update = doc! { "$set": set };
+    let result = collection
+        .clone_with_type::<Document>()
+        .find_one_and_update(filter, update)
+        .return_document(ReturnDocument::Before)
+        .await;
+
+    let before = match result {
+        Ok(Some(before)) => before,
+        Ok(None) => return AppError::NotFound("Document not found".to_string()).into_response(),
+        Err(e) if is_duplicate_key(&e) => {
+            return AppError::Conflict(DUPLICATE_NAME.to_string()).into_response();
+        }
+        Err(e) => return classify_db_error(&e).into_response(),
+    };
+
+    let changes = diff(&before, &update_data);
+    if changes.is_empty() {
+        let response_data = ApiResponse::new("No changes made", ());
+        return (StatusCode::OK, Json(response_data)).into_response();
+    }
+
+    let entry = AuditEntry {
+        id: None,
+        identity_id: id,
+        actor: email,
+        at: DateTime::now(),
+        changes,
+    };
+    if let Err(e) = audit.insert_one(entry).await {
+        error!("Failed to record audit entry for identity {} : {}", id, e);
+    }
+
+    let response_data = ApiResponse::new("Updated", ());
+    (StatusCode::OK, Json(response_data)).into_response()
+}
+
+/// Replaces an identity's whole label set in one write. Labels are
+/// deduplicated and limited as on creation.
+async fn replace_labels(
+    State(collection): State<Arc<Collection<Identity>>>,
+    State(audit): State<Arc<Collection<AuditEntry>>>,
+    Extension(email): Extension<String>,
+    Path(id): Path<ObjectId>,
+    Json(labels): Json<Vec<String>>,
+) -> impl IntoResponse {
+    let labels = match check_labels(labels) {
+        Ok(labels) => labels,
+        Err(e) => return AppError::Unprocessable(e).into_response(),
+    };
 
-    match result {
-        Ok(result) => {
-            let response_data = ApiResponse {
-                message: "Identity created".to_string(),
-                data: result.inserted_id,
-            };
-            (StatusCode::CREATED, Json(response_data)).into_response()
-        }
-        Err(e) => {
-            eprintln!("Internal Server Error : {}", e);
-            let response_data = ApiResponse {
-                message: "Internal Server Error".to_string(),
-                data: (),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(response_data)).into_response()
-        }
+    let update = doc! { "$set": { "labels": labels.clone(), "updated_at": DateTime::now() } };
+    match update_labels(&collection, doc! { "_id": id }, update).await {
+        Ok(Some(before)) => record_label_change(&audit, email, id, &before, &labels).await,
+        Ok(None) => return AppError::NotFound("Document not found".to_string()).into_response(),
+        Err(e) => return classify_db_error(&e).into_response(),
     }
+
+    let response_data = ApiResponse::new("Labels updated", labels);
+    (StatusCode::OK, Json(response_data)).into_response()
 }
 
-async fn get_all_identities(
+/// Adds one label to an identity. Adding a label it already has changes
+/// nothing; either way the response carries the resulting label set.
+async fn add_label(
     State(collection): State<Arc<Collection<Identity>>>,
+    State(audit): State<Arc<Collection<AuditEntry>>>,
+    Extension(email): Extension<String>,
+    Path((id, label)): Path<(ObjectId, String)>,
 ) -> impl IntoResponse {
-    match collection.find(doc! {}).await {
-        Ok(cursor) => match cursor.try_collect::<Vec<Identity>>().await {
-            Ok(result) => {
-                let response_data = ApiResponse {
-                    message: "Fetched all identities".to_string(),
-                    data: result,
-                };
+    if let Err(e) = check_labels(vec![label.clone()]) {
+        return AppError::Unprocessable(e).into_response();
+    }
+    // Only adds while the last allowed slot is free; with `MAX_LABELS=0`
+    // there is no such slot.
+    let Some(last_slot) = MAX_LABELS.checked_sub(1) else {
+        let message = format!("At most {} labels are allowed.", *MAX_LABELS);
+        return AppError::Unprocessable(message).into_response();
+    };
 
-                (StatusCode::OK, Json(response_data)).into_response()
-            }
-            Err(e) => {
-                eprintln!("Internal Server Error : {}", e);
-                let response_data = ApiResponse {
-                    message: "Internal Server Error".to_string(),
-                    data: Vec::<Identity>::new(),
-                };
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(response_data)).into_response()
+    let filter = doc! {
+        "_id": id,
+        "labels": { "$ne": &label },
+        format!("labels.{}", last_slot): { "$exists": false },
+    };
+    let update = doc! {
+        "$addToSet": { "labels": &label },
+        "$set": { "updated_at": DateTime::now() },
+    };
+    let labels = match update_labels(&collection, filter, update).await {
+        Ok(Some(before)) => {
+            let mut labels = labels_of(&before);
+            labels.push(label);
+            record_label_change(&audit, email, id, &before, &labels).await;
+            labels
+        }
+        Ok(None) => match current_labels(&collection, id).await {
+            Ok(labels) if labels.contains(&label) => labels,
+            Ok(_) => {
+                let message = format!("At most {} labels are allowed.", *MAX_LABELS);
+                return AppError::Unprocessable(message).into_response();
             }
+            Err(e) => return e.into_response(),
         },
-        Err(e) => {
-            eprintln!("Internal Server Error : {}", e);
-            let response_data = ApiResponse {
-                message: "Internal Server Error".to_string(),
-                data: Vec::<Identity>::new(),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(response_data)).into_response()
-        }
-    }
+        Err(e) => return classify_db_error(&e).into_response(),
+    };
+
+    let response_data = ApiResponse::new("Labels updated", labels);
+    (StatusCode::OK, Json(response_data)).into_response()
 }
 
-async fn get_identity(
+/// Removes one label from an identity. Removing a label it does not have
+/// changes nothing; either way the response carries the resulting label set.
+async fn remove_label(
     State(collection): State<Arc<Collection<Identity>>>,
-    Path(id): Path<ObjectId>,
+    State(audit): State<Arc<Collection<AuditEntry>>>,
+    Extension(email): Extension<String>,
+    Path((id, label)): Path<(ObjectId, String)>,
 ) -> impl IntoResponse {
-    let result = collection
-        .find_one(doc! {
-            "_id": id
+    let update = doc! {
+        "$pull": { "labels": &label },
+        "$set": { "updated_at": DateTime::now() },
+    };
+    let labels =
+        match update_labels(&collection, doc! { "_id": id, "labels": &label }, update).await {
+            Ok(Some(before)) => {
+                let mut labels = labels_of(&before);
+                labels.retain(|existing| *existing != label);
+                record_label_change(&audit, email, id, &before, &labels).await;
+                labels
+            }
+            Ok(None) => match current_labels(&collection, id).await {
+                Ok(labels) => labels,
+                Err(e) => return e.into_response(),
+            },
+            Err(e) => return classify_db_error(&e).into_response(),
+        };
+
+    let response_data = ApiResponse::new("Labels updated", labels);
+    (StatusCode::OK, Json(response_data)).into_response()
+}
+
+/// Labels stored on a live identity, or `404` when it does not exist.
+async fn current_labels(
+    collection: &Collection<Identity>,
+    id: ObjectId,
+) -> Result<Vec<String>, AppError> {
+    match collection.find_one(not_deleted(doc! { "_id": id })).await {
+        Ok(Some(identity)) => Ok(identity.labels),
+        Ok(None) => Err(AppError::NotFound("Document not found".to_string())),
+        Err(e) => Err(classify_db_error(&e)),
+    }
+}
+
+/// The `labels` array of a raw identity document; empty when absent.
+fn labels_of(document: &Document) -> Vec<String> {
+    document
+        .get_array("labels")
+        .map(|labels| {
+            labels
+                .iter()
+                .filter_map(|label| label.as_str().map(str::to_string))
+                .collect()
         })
-        .await;
+        .unwrap_or_default()
+}
 
-    match result {
-        Ok(Some(identity)) => {
-            let response_data = ApiResponse {
-                message: "Fetched".to_string(),
-                data: identity,
-            };
+/// Applies a labels `update` to the live identity matching `filter` and
+/// returns the document as it was before, or `None` when nothing matched.
+async fn update_labels(
+    collection: &Collection<Identity>,
+    filter: Document,
+    update: Document,
+) -> Result<Option<Document>, mongodb::error::Error> {
+    collection
+        .clone_with_type::<Document>()
+        .find_one_and_update(not_deleted(filter), update)
+        .return_document(ReturnDocument::Before)
+        .await
+}
 
-            (StatusCode::OK, Json(response_data)).into_response()
-        }
-        Ok(None) => {
-            let response_data = ApiResponse {
-                message: "Identity does not exist".to_string(),
-                data: (),
-            };
-            (StatusCode::NOT_FOUND, Json(response_data)).into_response()
-        }
-        Err(e) => {
-            eprintln!("Internal Server Error : {}", e);
-            let response_data = ApiResponse {
-                message: "Internal Server Error".to_string(),
-                data: (),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(response_data)).into_response()
-        }
+/// Records a label change in the identity's audit history, unless the label
+/// set came out the same.
+async fn record_label_change(
+    audit: &Collection<AuditEntry>,
+    actor: String,
+    id: ObjectId,
+    before: &Document,
+    labels: &[String],
+) {
+    let changes = diff(before, &doc! { "labels": labels });
+    if changes.is_empty() {
+        return;
+    }
+
+    let entry = AuditEntry {
+        id: None,
+        identity_id: id,
+        actor,
+        at: DateTime::now(),
+        changes,
+    };
+    if let Err(e) = audit.insert_one(entry).await {
+        error!("Failed to record audit entry for identity {} : {}", id, e);
     }
 }
 
-async fn update_identity(
+/// Lists an identity's recorded changes, newest first. Only the identity's
+/// owner and admins may read them.
+async fn get_identity_audit(
     State(collection): State<Arc<Collection<Identity>>>,
+    State(audit): State<Arc<Collection<AuditEntry>>>,
+    Extension(email): Extension<String>,
     Path(id): Path<ObjectId>,
-    Json(id_data): Json<IdentityUpdate>,
+    StrictQuery(params): StrictQuery<ListParams>,
 ) -> impl IntoResponse {
-    if let Err(e) = id_data.validate() {
-        return (StatusCode::BAD_REQUEST, e).into_response();
+    if params.sort.is_some() || params.after.is_some() {
+        return AppError::BadRequest(
+            "Audit entries only support 'page' and 'per_page'".to_string(),
+        )
+        .into_response();
+    }
+    if let Err(e) = params.validate() {
+        return AppError::BadRequest(e).into_response();
     }
 
-    let filter = doc! {
-        "_id":id
+    let identity = match collection.find_one(doc! { "_id": id }).await {
+        Ok(Some(identity)) => identity,
+        Ok(None) => {
+            return AppError::NotFound("Identity does not exist".to_string()).into_response();
+        }
+        Err(e) => return classify_db_error(&e).into_response(),
     };
+    if !is_admin(&email) && identity.owner_email.as_deref() != Some(email.as_str()) {
+        return AppError::Forbidden("Only the owner or an admin can read this history".to_string())
+            .into_response();
+    }
 
-    let update_data = match to_document(&id_data) {
-        Ok(document) => document,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    let filter = doc! { "identity_id": id };
+    let total = match audit.count_documents(filter.clone()).await {
+        Ok(total) => total,
+        Err(e) => return classify_db_error(&e).into_response(),
     };
 
-    let update = doc! { "$set": update_data };
-    let result = collection.update_one(filter, update).await;
+    let result = audit
+        .find(filter)
+        .sort(doc! { "at": -1, "_id": -1 })
+        .skip(params.skip())
+        .limit(params.per_page() as i64)
+        .await;
 
     match result {
-        Ok(data) => {
-            if data.matched_count == 0 {
-                let response_data = ApiResponse {
-                    message: "Document not found".to_string(),
-                    data: (),
-                };
-                (StatusCode::NOT_FOUND, Json(response_data)).into_response()
-            } else if data.modified_count == 0 {
-                let response_data = ApiResponse {
-                    message: "No changes made".to_string(),
-                    data: (),
-                };
-                (StatusCode::OK, Json(response_data)).into_response()
-            } else {
-                let response_data = ApiResponse {
-                    message: "Updated".to_string(),
-                    data: (),
-                };
-                (StatusCode::OK, Json(response_data)).into_response()
+        Ok(cursor) => match cursor.try_collect::<Vec<AuditEntry>>().await {
+            Ok(entries) => {
+                let entries: Vec<AuditEntryResponse> =
+                    entries.into_iter().map(Into::into).collect();
+                let response_data =
+                    ApiResponse::new("Fetched audit history", entries).with_total(total);
+                (
+                    StatusCode::OK,
+                    [("X-Total-Count", total.to_string())],
+                    Json(response_data),
+                )
+                    .into_response()
             }
-        }
-        Err(e) => {
-            eprintln!("Internal Server Error : {}", e);
-            let response_data = ApiResponse {
-                message: "Internal Server Error".to_string(),
-                data: (),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(response_data)).into_response()
-        }
+            Err(e) => classify_db_error(&e).into_response(),
+        },
+        Err(e) => classify_db_error(&e).into_response(),
     }
 }
 
+/// Soft-deletes an identity by stamping `deleted_at`. With `?hard=true`, an
+/// admin can instead remove the document for good, tombstoned or not.
 async fn delete_identity(
     State(collection): State<Arc<Collection<Identity>>>,
+    Extension(email): Extension<String>,
     Path(id): Path<ObjectId>,
+    StrictQuery(params): StrictQuery<DeleteParams>,
 ) -> impl IntoResponse {
-    let filter = doc! {"_id":id};
+    if params.hard {
+        if !is_admin(&email) {
+            return AppError::Forbidden("Hard delete requires admin access".to_string())
+                .into_response();
+        }
+
+        return match collection.delete_one(doc! { "_id": id }).await {
+            Ok(result_data) if result_data.deleted_count == 1 => {
+                let response_data = ApiResponse::new("Permanently deleted", ());
+                (StatusCode::OK, Json(response_data)).into_response()
+            }
+            Ok(_) => AppError::NotFound("Document not found".to_string()).into_response(),
+            Err(e) => classify_db_error(&e).into_response(),
+        };
+    }
 
-    let result = collection.delete_one(filter).await;
+    let filter = not_deleted(doc! { "_id": id });
+    let update = doc! { "$set": { "deleted_at": DateTime::now() } };
 
-    match result {
+    match collection.update_one(filter, update).await {
         Ok(result_data) => {
-            if result_data.deleted_count == 1 {
-                let response_data = ApiResponse {
-                    message: "Deleted".to_string(),
-                    data: (),
-                };
+            if result_data.matched_count == 1 {
+                let response_data = ApiResponse::new("Deleted", ());
                 (StatusCode::OK, Json(response_data)).into_response()
             } else {
-                let response_data = ApiResponse {
-                    message: "Document not found".to_string(),
-                    data: (),
-                };
-                (StatusCode::NOT_FOUND, Json(response_data)).into_response()
+                AppError::NotFound("Document not found".to_string()).into_response()
             }
         }
-        Err(e) => {
-            eprintln!("Internal Server Error : {}", e);
-            let response_data = ApiResponse {
-                message: "Internal Server Error".to_string(),
-                data: (),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(response_data)).into_response()
-        }
+        Err(e) => classify_db_error(&e).into_response(),
     }
 }
 
+/// Restricts `filter` to identities that have not been soft-deleted.
+fn not_deleted(mut filter: Document) -> Document {
+    filter.insert("deleted_at", doc! { "$exists": false });
+    filter
+}
+
 async fn signup(
     State(collection): State<Arc<Collection<Auth>>>,
+    client_ip: ClientIp,
     Json(credentials): Json<Auth>,
 ) -> impl IntoResponse {
     let argon2 = Argon2::default();
     let salt = SaltString::generate(&mut OsRng);
 
-    let password_hash = match argon2.hash_password(&credentials.password.as_bytes(), &salt) {
+    let password_hash = match argon2.hash_password(credentials.password.as_bytes(), &salt) {
         Ok(hash) => hash.to_string(),
-        Err(e) => {
-            eprintln!("Internal Server Error : {}", e);
-            let response_data = ApiResponse {
-                message: "Internal Server Error".to_string(),
-                data: (),
-            };
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(response_data)).into_response();
-        }
+        Err(e) => return AppError::internal(e).into_response(),
     };
 
+    // No lookup before the insert: concurrent signups for one email could both
+    // pass it. The unique email index decides instead, so exactly one insert
+    // wins and the others fail with a duplicate key.
+    let email = log_email(&credentials.email);
     let result = collection
         .insert_one(Auth {
             email: credentials.email,
             password: password_hash,
+            pending_email: None,
+            pending_email_token: None,
+            pending_email_expires_at: None,
+            token_version: i64::from(OsRng.next_u32()),
         })
         .await;
 
     match result {
         Ok(result) => {
-            let response_data = ApiResponse {
-                message: "Auth created".to_string(),
-                data: result.inserted_id,
-            };
+            info!(
+                outcome = "success",
+                email,
+                ip = client_ip.display(),
+                "signup"
+            );
+            let response_data = ApiResponse::new("Auth created", result.inserted_id);
             (StatusCode::CREATED, Json(response_data)).into_response()
         }
-        Err(e) => {
-            eprintln!("Internal Server Error : {}", e);
-            let response_data = ApiResponse {
-                message: "Internal Server Error".to_string(),
-                data: (),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(response_data)).into_response()
+        Err(e) if is_duplicate_key(&e) => {
+            warn!(
+                outcome = "duplicate",
+                email,
+                ip = client_ip.display(),
+                "signup"
+            );
+            AppError::Conflict("Email already registered".to_string()).into_response()
         }
+        Err(e) => classify_db_error(&e).into_response(),
     }
 }
 
 async fn login(
     State(collection): State<Arc<Collection<Auth>>>,
+    client_ip: ClientIp,
     Json(credentials): Json<Auth>,
 ) -> impl IntoResponse {
+    let email = log_email(&credentials.email);
     let result = collection
         .find_one(doc! { "email" : credentials.email })
         .await;
@@ -390,69 +1986,239 @@ async fn login(
     let credentials_doc = match result {
         Ok(Some(result)) => result,
         Ok(None) => {
-            let response_data = ApiResponse {
-                message: "Credential does not exist".to_string(),
-                data: (),
-            };
-            return (StatusCode::NOT_FOUND, Json(response_data)).into_response();
-        }
-        Err(e) => {
-            eprintln!("Internal Server Error : {}", e);
-            let response_data = ApiResponse {
-                message: "Internal Server Error".to_string(),
-                data: (),
-            };
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(response_data)).into_response();
+            warn!(
+                outcome = "failure",
+                reason = "unknown_email",
+                email,
+                ip = client_ip.display(),
+                "login"
+            );
+            if *LOGIN_EQUALIZE_TIMING
+                && let Ok(dummy_hash) = PasswordHash::new(&DUMMY_PASSWORD_HASH)
+            {
+                let _ =
+                    Argon2::default().verify_password(credentials.password.as_bytes(), &dummy_hash);
+            }
+            return AppError::Unauthorized(INVALID_CREDENTIALS.to_string()).into_response();
         }
+        Err(e) => return classify_db_error(&e).into_response(),
     };
 
     let parsed_hash = match PasswordHash::new(&credentials_doc.password) {
         Ok(hash) => hash,
-        Err(e) => {
-            eprintln!("Internal Server Error : {}", e);
-            let response_data = ApiResponse {
-                message: "Internal Server Error".to_string(),
-                data: (),
-            };
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(response_data)).into_response();
-        }
+        Err(e) => return AppError::internal(e).into_response(),
     };
 
-    if let Err(e) =
-        Argon2::default().verify_password(&credentials.password.as_bytes(), &parsed_hash)
+    if Argon2::default()
+        .verify_password(credentials.password.as_bytes(), &parsed_hash)
+        .is_err()
     {
-        eprintln!("Invalid Password : {}", e);
-        let response = ApiResponse {
-            message: "Invalid Password".to_string(),
-            data: (),
-        };
-        return (StatusCode::UNAUTHORIZED, Json(response)).into_response();
+        warn!(
+            outcome = "failure",
+            reason = "invalid_password",
+            email,
+            ip = client_ip.display(),
+            "login"
+        );
+        return AppError::Unauthorized(INVALID_CREDENTIALS.to_string()).into_response();
     };
 
-    let auth_token = match generate_token(&credentials_doc.email) {
+    let auth_token = match generate_token(&credentials_doc.email, credentials_doc.token_version) {
         Ok(token) => token,
-        Err(e) => {
-            eprintln!("Internal Server Error while generating auth token: {}", e);
-            let response_data = ApiResponse {
-                message: "Internal Server Error".to_string(),
-                data: (),
-            };
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(response_data)).into_response();
+        Err(e) => return AppError::internal(e).into_response(),
+    };
+
+    info!(
+        outcome = "success",
+        email,
+        ip = client_ip.display(),
+        "login"
+    );
+
+    let response = ApiResponse::new("You are logged in", auth_token);
+
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+fn is_valid_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+/// Random URL-safe token for one-off links.
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// One-off tokens are stored hashed so a database leak does not expose live links.
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Delivers the confirmation link for a pending email change. No mailer is
+/// wired in yet, so the link is logged for an operator to forward.
+fn send_verification_email(email: &str, link: &str) {
+    info!(email = log_email(email), link, "email_verification");
+}
+
+/// Changes the caller's email. With `EMAIL_VERIFICATION`, the new address stays
+/// pending, and the current one active, until the link sent to it is confirmed;
+/// otherwise the change applies at once.
+async fn change_email(
+    State(auth): State<Arc<Collection<Auth>>>,
+    State(identities): State<Arc<Collection<Identity>>>,
+    Extension(email): Extension<String>,
+    Json(change): Json<EmailChange>,
+) -> Response {
+    let new_email = change.email.trim().to_string();
+    if !is_valid_email(&new_email) {
+        return AppError::BadRequest("Invalid email address".to_string()).into_response();
+    }
+    if new_email == email {
+        return AppError::BadRequest("This is already your email".to_string()).into_response();
+    }
+    match auth.find_one(doc! { "email": &new_email }).await {
+        Ok(Some(_)) => {
+            return AppError::Conflict("Email already registered".to_string()).into_response();
+        }
+        Ok(None) => {}
+        Err(e) => return classify_db_error(&e).into_response(),
+    }
+
+    if !*EMAIL_VERIFICATION {
+        return apply_email_change(&auth, &identities, &email, &new_email).await;
+    }
+
+    let token = random_token();
+    let expires_at = DateTime::from_millis(
+        DateTime::now().timestamp_millis() + *EMAIL_VERIFICATION_TTL_SECS * 1000,
+    );
+    let update = doc! { "$set": {
+        "pending_email": &new_email,
+        "pending_email_token": hash_token(&token),
+        "pending_email_expires_at": expires_at,
+    } };
+    match auth.update_one(doc! { "email": &email }, update).await {
+        Ok(result) if result.matched_count == 0 => {
+            return AppError::NotFound("Account not found".to_string()).into_response();
         }
+        Ok(_) => {}
+        Err(e) => return classify_db_error(&e).into_response(),
+    }
+
+    let link = format!("{}/auth/confirm-email?token={}", *PUBLIC_BASE_URL, token);
+    send_verification_email(&new_email, &link);
+    info!(
+        outcome = "pending",
+        email = log_email(&email),
+        "email_change"
+    );
+
+    let response_data = ApiResponse::new(
+        "Confirm the new email using the link sent to it; your current email stays active until then",
+        (),
+    );
+    (StatusCode::ACCEPTED, Json(response_data)).into_response()
+}
+
+/// Activates a pending email change from the link sent to the new address.
+async fn confirm_email(
+    State(auth): State<Arc<Collection<Auth>>>,
+    State(identities): State<Arc<Collection<Identity>>>,
+    StrictQuery(confirmation): StrictQuery<EmailConfirmation>,
+) -> Response {
+    let invalid_link =
+        || AppError::BadRequest("Invalid or expired confirmation link".to_string()).into_response();
+
+    let account = match auth
+        .find_one(doc! { "pending_email_token": hash_token(&confirmation.token) })
+        .await
+    {
+        Ok(Some(account)) => account,
+        Ok(None) => return invalid_link(),
+        Err(e) => return classify_db_error(&e).into_response(),
     };
+    match (account.pending_email, account.pending_email_expires_at) {
+        (Some(new_email), Some(expires_at)) if expires_at > DateTime::now() => {
+            apply_email_change(&auth, &identities, &account.email, &new_email).await
+        }
+        _ => invalid_link(),
+    }
+}
 
-    let response = ApiResponse {
-        message: "You are logged in".to_string(),
-        data: auth_token,
+/// Moves the account, and the identities it owns, from `old_email` to
+/// `new_email`, then issues a token for the new address. Bumping the
+/// account's `token_version` revokes every token issued before the change.
+async fn apply_email_change(
+    auth: &Collection<Auth>,
+    identities: &Collection<Identity>,
+    old_email: &str,
+    new_email: &str,
+) -> Response {
+    let update = doc! {
+        "$set": { "email": new_email },
+        "$unset": { "pending_email": "", "pending_email_token": "", "pending_email_expires_at": "" },
+        "$inc": { "token_version": 1 },
+    };
+    let account = match auth
+        .find_one_and_update(doc! { "email": old_email }, update)
+        .return_document(ReturnDocument::After)
+        .await
+    {
+        Ok(Some(account)) => account,
+        Ok(None) => {
+            return AppError::NotFound("Account not found".to_string()).into_response();
+        }
+        Err(e) if is_duplicate_key(&e) => {
+            return AppError::Conflict("Email already registered".to_string()).into_response();
+        }
+        Err(e) => return classify_db_error(&e).into_response(),
     };
 
-    (StatusCode::OK, Json(response)).into_response()
+    let moved = identities
+        .update_many(
+            doc! { "owner_email": old_email },
+            doc! { "$set": { "owner_email": new_email } },
+        )
+        .await;
+    if let Err(e) = moved {
+        error!(
+            old_email = log_email(old_email),
+            new_email = log_email(new_email),
+            "Failed to move identities to the new email : {}",
+            e
+        );
+    }
+    info!(
+        outcome = "success",
+        email = log_email(new_email),
+        "email_change"
+    );
+
+    let auth_token = match generate_token(new_email, account.token_version) {
+        Ok(token) => token,
+        Err(e) => return AppError::internal(e).into_response(),
+    };
+    let response_data = ApiResponse::new("Email updated", auth_token);
+    (StatusCode::OK, Json(response_data)).into_response()
 }
 
-fn generate_token(email: &str) -> Result<String, jsonwebtoken::errors::Error> {
+fn generate_token(email: &str, token_version: i64) -> Result<String, jsonwebtoken::errors::Error> {
     let my_claims = Claims {
         sub: email.to_string(),
         exp: get_current_timestamp() + Duration::new(3600, 0).as_secs(),
+        token_version,
     };
     encode(
         &Header::default(),
@@ -461,57 +2227,102 @@ fn generate_token(email: &str) -> Result<String, jsonwebtoken::errors::Error> {
     )
 }
 
+/// Verifies a token against `SECRET_KEY`, then each of `SECRET_KEY_PREVIOUS`,
+/// so tokens issued before a secret rotation stay valid until they expire.
+fn verify_token(token: &str) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
+    let mut result = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(SECRET_KEY.as_bytes()),
+        &Validation::default(),
+    );
+
+    for secret in SECRET_KEY_PREVIOUS.iter() {
+        match &result {
+            Err(e) if matches!(e.kind(), JwtErrorKind::InvalidSignature) => {
+                result = decode::<Claims>(
+                    token,
+                    &DecodingKey::from_secret(secret.as_bytes()),
+                    &Validation::default(),
+                );
+            }
+            _ => break,
+        }
+    }
+
+    result
+}
+
+/// Claims of a valid bearer token, or `None` for anonymous callers and invalid
+/// tokens. Never rejects, so public routes can tailor responses to signed-in users.
+struct OptionalClaims(Option<Claims>);
+
+impl<S: Send + Sync> FromRequestParts<S> for OptionalClaims {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let claims = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+            .and_then(|(_, token)| verify_token(token.trim()).ok())
+            .map(|token_data| token_data.claims);
+        Ok(OptionalClaims(claims))
+    }
+}
+
 async fn login_required(
     State(collection): State<Arc<Collection<Auth>>>,
     mut req: Request,
     next: Next,
 ) -> impl IntoResponse {
+    let ip = client_ip(req.headers(), req.extensions())
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
     let headers = match req.headers().get("Authorization") {
         Some(headers) => match headers.to_str() {
             Ok(headers) => headers,
             Err(e) => {
-                eprintln!("Internal Server Error : {}", e);
-                let response_data = ApiResponse {
-                    message: "Internal Server Error".to_string(),
-                    data: (),
-                };
-                return (StatusCode::INTERNAL_SERVER_ERROR, Json(response_data)).into_response();
+                warn!(
+                    outcome = "reject",
+                    reason = "invalid_header",
+                    ip,
+                    "auth: {}",
+                    e
+                );
+                return AppError::BadRequest("Invalid Authorization header".to_string())
+                    .into_response();
             }
         },
         None => {
-            eprintln!("Missing headers");
-            let response_data = ApiResponse {
-                message: "Missing headers".to_string(),
-                data: (),
-            };
+            warn!(outcome = "reject", reason = "missing_header", ip, "auth");
+            let response_data = ApiResponse::new("Missing headers", ());
             return (StatusCode::BAD_REQUEST, Json(response_data)).into_response();
         }
     };
     let split_headers = headers.split_whitespace().collect::<Vec<&str>>();
 
     if split_headers.len() != 2 {
-        eprintln!("Invalid Token Format");
-        let response_data = ApiResponse {
-            message: "Invalid Token Format".to_string(),
-            data: (),
-        };
+        warn!(outcome = "reject", reason = "invalid_format", ip, "auth");
+        let response_data = ApiResponse::new("Invalid Token Format", ());
         return (StatusCode::BAD_REQUEST, Json(response_data)).into_response();
     }
 
     let token = split_headers[1];
 
-    let token_data = match decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(SECRET_KEY.as_bytes()),
-        &Validation::default(),
-    ) {
+    let token_data = match verify_token(token) {
         Ok(token_data) => token_data,
         Err(e) => {
-            eprintln!("Token Error : {}", e);
-            let response_data = ApiResponse {
-                message: e.to_string(),
-                data: (),
-            };
+            warn!(
+                outcome = "reject",
+                reason = "invalid_token",
+                ip,
+                "auth: {}",
+                e
+            );
+            let response_data = ApiResponse::new(e.to_string(), ());
             return (StatusCode::BAD_REQUEST, Json(response_data)).into_response();
         }
     };
@@ -524,19 +2335,299 @@ async fn login_required(
         .await;
 
     match result {
-        Ok(_) => {
+        Ok(Some(account)) if account.token_version == token_data.claims.token_version => {
             req.extensions_mut().insert(email);
             next.run(req).await
         }
-        Err(err) => (StatusCode::UNAUTHORIZED, err.to_string()).into_response(),
+        Ok(Some(_)) => {
+            warn!(
+                outcome = "reject",
+                reason = "revoked_token",
+                email = log_email(&email),
+                ip,
+                "auth"
+            );
+            AppError::Unauthorized("Token has been revoked".to_string()).into_response()
+        }
+        Ok(None) => {
+            warn!(
+                outcome = "reject",
+                reason = "unknown_user",
+                email = log_email(&email),
+                ip,
+                "auth"
+            );
+            AppError::Unauthorized("Account no longer exists".to_string()).into_response()
+        }
+        Err(err) => {
+            warn!(
+                outcome = "reject",
+                reason = "lookup_failed",
+                email = log_email(&email),
+                ip,
+                "auth: {}",
+                err
+            );
+            classify_db_error(&err).into_response()
+        }
     }
 }
 
-async fn protected(Extension(email): Extension<String>) -> impl IntoResponse {
-    let response = ApiResponse {
-        message: format!("Hello. You are logged in using {}", email),
-        data: {},
+/// Compares secrets through their digests so the comparison time does not
+/// reveal how much of a guess was right.
+fn secrets_match(given: &[u8], expected: &[u8]) -> bool {
+    Sha256::digest(given) == Sha256::digest(expected)
+}
+
+fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local(),
+    }
+}
+
+/// Guards operational endpoints with `OPS_AUTH_TOKEN` (bearer) or `OPS_AUTH_BASIC`
+/// credentials, independent of user JWTs. Open when neither is configured.
+async fn ops_auth(req: Request, next: Next) -> Response {
+    if OPS_AUTH_TOKEN.is_none() && OPS_AUTH_BASIC.is_none() {
+        return next.run(req).await;
+    }
+    if *OPS_AUTH_EXEMPT_PRIVATE
+        && client_ip(req.headers(), req.extensions()).is_some_and(is_private_ip)
+    {
+        return next.run(req).await;
+    }
+
+    let authorized = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .is_some_and(|(scheme, credentials)| {
+            let credentials = credentials.trim();
+            if scheme.eq_ignore_ascii_case("Bearer") {
+                OPS_AUTH_TOKEN
+                    .as_deref()
+                    .is_some_and(|token| secrets_match(credentials.as_bytes(), token.as_bytes()))
+            } else if scheme.eq_ignore_ascii_case("Basic") {
+                OPS_AUTH_BASIC.as_deref().is_some_and(|basic| {
+                    BASE64
+                        .decode(credentials)
+                        .is_ok_and(|decoded| secrets_match(&decoded, basic.as_bytes()))
+                })
+            } else {
+                false
+            }
+        });
+    if authorized {
+        return next.run(req).await;
+    }
+
+    warn!(outcome = "reject", path = %req.uri().path(), "ops_auth");
+    let mut response = AppError::Unauthorized("Credentials required".to_string()).into_response();
+    if OPS_AUTH_TOKEN.is_some() {
+        response
+            .headers_mut()
+            .append(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    }
+    if OPS_AUTH_BASIC.is_some() {
+        response.headers_mut().append(
+            WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"ops\""),
+        );
+    }
+    response
+}
+
+/// Rejects callers whose email is not listed in `ADMIN_EMAILS`. Must run after `login_required`.
+async fn admin_required(req: Request, next: Next) -> Response {
+    match req.extensions().get::<String>() {
+        Some(email) if is_admin(email) => next.run(req).await,
+        _ => AppError::Forbidden("Admin access required".to_string()).into_response(),
+    }
+}
+
+/// Verifies up to `MAX_BATCH_SIZE` tokens in one call, reporting each token's
+/// claims or the reason it was rejected. Only signatures and expiry are
+/// checked; unlike `login_required`, the account is not looked up.
+async fn verify_token_batch(Json(tokens): Json<Vec<String>>) -> impl IntoResponse {
+    if tokens.len() > *MAX_BATCH_SIZE {
+        let response_data = ApiResponse::new(
+            format!("Batch exceeds the maximum size of {}", *MAX_BATCH_SIZE),
+            (),
+        );
+        return (StatusCode::PAYLOAD_TOO_LARGE, Json(response_data)).into_response();
+    }
+
+    let results = tokens
+        .iter()
+        .enumerate()
+        .map(|(index, token)| match verify_token(token) {
+            Ok(token_data) => TokenVerification {
+                index,
+                valid: true,
+                claims: Some(token_data.claims),
+                error: None,
+            },
+            Err(e) => TokenVerification {
+                index,
+                valid: false,
+                claims: None,
+                error: Some(match e.kind() {
+                    JwtErrorKind::ExpiredSignature => "expired",
+                    JwtErrorKind::InvalidSignature => "invalid_signature",
+                    JwtErrorKind::InvalidToken
+                    | JwtErrorKind::Base64(_)
+                    | JwtErrorKind::Json(_)
+                    | JwtErrorKind::Utf8(_) => "malformed",
+                    _ => "invalid",
+                }),
+            },
+        })
+        .collect::<Vec<TokenVerification>>();
+
+    let response_data = ApiResponse::new("Verified tokens", results);
+    (StatusCode::OK, Json(response_data)).into_response()
+}
+
+async fn db_stats() -> impl IntoResponse {
+    let response_data = ApiResponse::new("Connection pool statistics", METRICS.pool_stats());
+    (StatusCode::OK, Json(response_data))
+}
+
+/// Database reachability and pool figures for operators; `503` while the
+/// database does not answer a ping.
+async fn health_detailed(State(database): State<Database>) -> impl IntoResponse {
+    let health = check_health(&database).await;
+    if health.database == "up" {
+        (StatusCode::OK, Json(ApiResponse::new("Healthy", health)))
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ApiResponse::new("Unhealthy", health)),
+        )
+    }
+}
+
+/// Pings the database and reports the outcome alongside the pool figures.
+async fn check_health(database: &Database) -> HealthReport {
+    let started = Instant::now();
+    let ping = database.run_command(doc! { "ping": 1 }).await;
+    if let Err(e) = &ping {
+        warn!("Health check ping failed : {}", e);
+    }
+
+    HealthReport {
+        database: if ping.is_ok() { "up" } else { "down" },
+        ping_ms: started.elapsed().as_millis() as u64,
+        pool: METRICS.pool_stats(),
+    }
+}
+
+/// Build, runtime, configuration and database status in one place for
+/// admins. Secrets in the configuration are redacted; the response is `200`
+/// even while the database is down, which `health.database` reports.
+async fn diagnostics(State(database): State<Database>) -> impl IntoResponse {
+    let report = Diagnostics {
+        build: BuildInfo {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: option_env!("GIT_COMMIT"),
+            profile: if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            },
+        },
+        uptime_secs: STARTED_AT.elapsed().as_secs(),
+        process: process_stats(),
+        health: check_health(&database).await,
+        config: effective_config(),
+    };
+    (
+        StatusCode::OK,
+        Json(ApiResponse::new("Diagnostics", report)),
+    )
+}
+
+/// Reads memory, thread and CPU usage from `/proc/self` on Linux.
+fn process_stats() -> ProcessStats {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let status_field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.split_whitespace().next())
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+
+    // utime and stime are the 14th and 15th fields, in USER_HZ (100 on Linux)
+    // ticks; the command name before them may contain spaces, so count from
+    // the closing parenthesis.
+    let stat = std::fs::read_to_string("/proc/self/stat").unwrap_or_default();
+    let cpu_seconds = stat.rsplit_once(')').and_then(|(_, fields)| {
+        let fields = fields.split_whitespace().collect::<Vec<&str>>();
+        let utime = fields.get(11)?.parse::<u64>().ok()?;
+        let stime = fields.get(12)?.parse::<u64>().ok()?;
+        Some((utime + stime) as f64 / 100.0)
+    });
+
+    ProcessStats {
+        pid: std::process::id(),
+        rss_bytes: status_field("VmRSS:").map(|kib| kib * 1024),
+        threads: status_field("Threads:"),
+        cpu_seconds,
+    }
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+}
+
+async fn meta() -> impl IntoResponse {
+    let hints = ClientHints {
+        default_page_size: *DEFAULT_PAGE_SIZE,
+        max_page_size: *MAX_PAGE_SIZE,
+        max_pagination_offset: *MAX_PAGINATION_OFFSET,
+        allowed_sort_fields: ALLOWED_SORT_FIELDS,
+        max_body_bytes: *MAX_BODY_BYTES,
+        max_batch_size: *MAX_BATCH_SIZE,
+        max_labels: *MAX_LABELS,
+        max_label_length: *MAX_LABEL_LENGTH,
+        user_rate_limit: *USER_RATE_LIMIT,
+        user_rate_limit_window_secs: *USER_RATE_LIMIT_WINDOW_SECS,
+    };
+    (
+        StatusCode::OK,
+        Json(ApiResponse::new("Client hints", hints)),
+    )
+}
+
+/// Reports the server clock; signed-in callers also learn when their token expires.
+async fn server_time(OptionalClaims(claims): OptionalClaims) -> impl IntoResponse {
+    let utc = match DateTime::now().try_to_rfc3339_string() {
+        Ok(utc) => utc,
+        Err(e) => return AppError::internal(e).into_response(),
     };
 
+    let response_data = ApiResponse::new(
+        "Server time",
+        ServerTime {
+            utc,
+            timezone: "UTC",
+            unix: get_current_timestamp(),
+            token_expires_in: claims
+                .map(|claims| claims.exp.saturating_sub(get_current_timestamp())),
+        },
+    );
+    (StatusCode::OK, Json(response_data)).into_response()
+}
+
+async fn protected(Extension(email): Extension<String>) -> impl IntoResponse {
+    let response = ApiResponse::new(format!("Hello. You are logged in using {}", email), ());
+
     (StatusCode::OK, Json(response))
 }
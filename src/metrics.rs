@@ -0,0 +1,219 @@
+use mongodb::event::{cmap::CmapEvent, command::CommandEvent};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Process-wide metrics registry, fed by driver event handlers and rendered at `/metrics`.
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+#[derive(Default)]
+pub struct Metrics {
+    connections_created: AtomicU64,
+    connections_closed: AtomicU64,
+    connections_checked_out: AtomicU64,
+    connections_checked_in: AtomicU64,
+    checkout_failures: AtomicU64,
+    checkout_micros: AtomicU64,
+    commands: Mutex<BTreeMap<String, CommandStats>>,
+}
+
+/// Latency totals for one MongoDB command name.
+#[derive(Debug, Default, Clone, Copy)]
+struct CommandStats {
+    count: u64,
+    failures: u64,
+    seconds: f64,
+}
+
+/// Snapshot of the MongoDB connection pool gauges.
+#[derive(Debug, Serialize)]
+pub struct PoolStats {
+    pub connections_open: u64,
+    pub connections_in_use: u64,
+    pub connections_idle: u64,
+    pub checkouts_total: u64,
+    pub checkout_failures_total: u64,
+}
+
+impl Metrics {
+    pub fn record_cmap_event(&self, event: &CmapEvent) {
+        let counter = match event {
+            CmapEvent::ConnectionCreated(_) => &self.connections_created,
+            CmapEvent::ConnectionClosed(_) => &self.connections_closed,
+            CmapEvent::ConnectionCheckedOut(checked_out) => {
+                self.checkout_micros
+                    .fetch_add(checked_out.duration.as_micros() as u64, Ordering::Relaxed);
+                &self.connections_checked_out
+            }
+            CmapEvent::ConnectionCheckedIn(_) => &self.connections_checked_in,
+            CmapEvent::ConnectionCheckoutFailed(_) => &self.checkout_failures,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_command_event(&self, event: &CommandEvent) {
+        let (command_name, duration, failed) = match event {
+            CommandEvent::Succeeded(succeeded) => {
+                (&succeeded.command_name, succeeded.duration, false)
+            }
+            CommandEvent::Failed(failed) => (&failed.command_name, failed.duration, true),
+            _ => return,
+        };
+        self.record_command(command_name, duration, failed);
+    }
+
+    fn record_command(&self, command_name: &str, duration: Duration, failed: bool) {
+        let mut commands = self.commands.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = commands.entry(command_name.to_string()).or_default();
+        stats.count += 1;
+        stats.seconds += duration.as_secs_f64();
+        if failed {
+            stats.failures += 1;
+        }
+    }
+
+    pub fn pool_stats(&self) -> PoolStats {
+        let open = self
+            .connections_created
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.connections_closed.load(Ordering::Relaxed));
+        let checkouts = self.connections_checked_out.load(Ordering::Relaxed);
+        let in_use = checkouts.saturating_sub(self.connections_checked_in.load(Ordering::Relaxed));
+
+        PoolStats {
+            connections_open: open,
+            connections_in_use: in_use,
+            connections_idle: open.saturating_sub(in_use),
+            checkouts_total: checkouts,
+            checkout_failures_total: self.checkout_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let pool = self.pool_stats();
+        let mut output = String::new();
+
+        for (name, kind, help, value) in [
+            (
+                "mongodb_pool_connections_open",
+                "gauge",
+                "Connections currently held by the MongoDB pool.",
+                pool.connections_open,
+            ),
+            (
+                "mongodb_pool_connections_in_use",
+                "gauge",
+                "Pooled connections currently checked out.",
+                pool.connections_in_use,
+            ),
+            (
+                "mongodb_pool_connections_idle",
+                "gauge",
+                "Pooled connections waiting to be checked out.",
+                pool.connections_idle,
+            ),
+            (
+                "mongodb_pool_checkouts_total",
+                "counter",
+                "Connections checked out of the pool.",
+                pool.checkouts_total,
+            ),
+            (
+                "mongodb_pool_checkout_failures_total",
+                "counter",
+                "Failed attempts to check a connection out of the pool.",
+                pool.checkout_failures_total,
+            ),
+        ] {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} {}", name, kind);
+            let _ = writeln!(output, "{} {}", name, value);
+        }
+
+        let _ = writeln!(
+            output,
+            "# HELP mongodb_pool_checkout_duration_seconds Time spent checking connections out of the pool."
+        );
+        let _ = writeln!(
+            output,
+            "# TYPE mongodb_pool_checkout_duration_seconds summary"
+        );
+        let _ = writeln!(
+            output,
+            "mongodb_pool_checkout_duration_seconds_sum {}",
+            self.checkout_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(
+            output,
+            "mongodb_pool_checkout_duration_seconds_count {}",
+            pool.checkouts_total
+        );
+
+        let commands = self
+            .commands
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let _ = writeln!(
+            output,
+            "# HELP mongodb_command_duration_seconds Latency of MongoDB commands by command name."
+        );
+        let _ = writeln!(output, "# TYPE mongodb_command_duration_seconds summary");
+        for (command, stats) in &commands {
+            let _ = writeln!(
+                output,
+                "mongodb_command_duration_seconds_sum{{command=\"{}\"}} {}",
+                command, stats.seconds
+            );
+            let _ = writeln!(
+                output,
+                "mongodb_command_duration_seconds_count{{command=\"{}\"}} {}",
+                command, stats.count
+            );
+        }
+        let _ = writeln!(
+            output,
+            "# HELP mongodb_command_failures_total Failed MongoDB commands by command name."
+        );
+        let _ = writeln!(output, "# TYPE mongodb_command_failures_total counter");
+        for (command, stats) in &commands {
+            let _ = writeln!(
+                output,
+                "mongodb_command_failures_total{{command=\"{}\"}} {}",
+                command, stats.failures
+            );
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_includes_recorded_commands() {
+        let metrics = Metrics::default();
+        metrics.record_command("find", Duration::from_millis(250), false);
+        metrics.record_command("find", Duration::from_millis(250), true);
+        metrics.record_command("insert", Duration::from_millis(100), false);
+
+        let output = metrics.render();
+        assert!(output.contains("mongodb_command_duration_seconds_sum{command=\"find\"} 0.5\n"));
+        assert!(output.contains("mongodb_command_duration_seconds_count{command=\"find\"} 2\n"));
+        assert!(output.contains("mongodb_command_failures_total{command=\"find\"} 1\n"));
+        assert!(output.contains("mongodb_command_duration_seconds_count{command=\"insert\"} 1\n"));
+        assert!(output.contains("mongodb_command_failures_total{command=\"insert\"} 0\n"));
+    }
+}
@@ -0,0 +1,352 @@
+use crate::config::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE, MAX_PAGINATION_OFFSET};
+use crate::error::AppError;
+
+use axum::{
+    extract::{FromRequestParts, Query},
+    http::request::Parts,
+    response::{IntoResponse, Response},
+};
+use mongodb::bson::{Bson, Document, doc, oid::ObjectId};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+
+/// Fields clients may sort listings by, optionally prefixed with `-` for descending.
+pub const ALLOWED_SORT_FIELDS: &[&str] = &["name", "age"];
+
+/// Pagination and sorting accepted by every identity listing. `after` switches
+/// to keyset pagination: results resume after that `_id` instead of skipping.
+#[derive(Debug, Default, Deserialize)]
+pub struct ListParams {
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
+    pub sort: Option<String>,
+    pub after: Option<ObjectId>,
+}
+
+impl ListParams {
+    pub fn page(&self) -> u64 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    pub fn per_page(&self) -> u64 {
+        self.per_page
+            .unwrap_or(*DEFAULT_PAGE_SIZE)
+            .clamp(1, *MAX_PAGE_SIZE)
+    }
+
+    pub fn skip(&self) -> u64 {
+        if self.after.is_some() {
+            return 0;
+        }
+        (self.page() - 1).saturating_mul(self.per_page())
+    }
+
+    /// Rejects parameter combinations that cannot be served efficiently.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.after.is_some() && (self.sort.is_some() || self.page.is_some()) {
+            return Err("'after' cannot be combined with 'page' or 'sort'".to_string());
+        }
+        if self.skip() > *MAX_PAGINATION_OFFSET {
+            return Err(format!(
+                "Offset {} exceeds the maximum of {}. Use keyset pagination instead: \
+                 pass the last _id you received as 'after'.",
+                self.skip(),
+                *MAX_PAGINATION_OFFSET
+            ));
+        }
+        Ok(())
+    }
+
+    /// Restricts `filter` to documents after the keyset cursor, if one was given.
+    pub fn apply_cursor(&self, filter: Document) -> Document {
+        match self.after {
+            Some(after) => doc! { "$and": [filter, { "_id": { "$gt": after } }] },
+            None => filter,
+        }
+    }
+
+    /// Sort document for the requested field, falling back to `_id` so pages stay stable.
+    pub fn sort(&self) -> Result<Document, String> {
+        let Some(sort) = self.sort.as_deref() else {
+            return Ok(doc! { "_id": 1 });
+        };
+
+        let (field, direction) = match sort.strip_prefix('-') {
+            Some(field) => (field, -1),
+            None => (sort, 1),
+        };
+        if !ALLOWED_SORT_FIELDS.contains(&field) {
+            return Err(format!(
+                "Cannot sort by '{}'. Allowed fields: {}",
+                field,
+                ALLOWED_SORT_FIELDS.join(", ")
+            ));
+        }
+
+        Ok(doc! { field: direction, "_id": 1 })
+    }
+}
+
+/// A query such as `{ "name": { "eq": "Alice" }, "age": { "gte": 18, "lt": 65 } }`,
+/// mapping each field to the operators applied to it.
+pub type QueryDsl = BTreeMap<String, BTreeMap<String, Value>>;
+
+#[derive(Clone, Copy)]
+enum FieldType {
+    String,
+    Age,
+}
+
+fn field_type(field: &str) -> Option<FieldType> {
+    match field {
+        "name" => Some(FieldType::String),
+        "age" => Some(FieldType::Age),
+        _ => None,
+    }
+}
+
+fn mongo_operator(operator: &str) -> Option<&'static str> {
+    match operator {
+        "eq" => Some("$eq"),
+        "ne" => Some("$ne"),
+        "gt" => Some("$gt"),
+        "gte" => Some("$gte"),
+        "lt" => Some("$lt"),
+        "lte" => Some("$lte"),
+        "in" => Some("$in"),
+        _ => None,
+    }
+}
+
+fn to_bson(field: &str, field_type: FieldType, value: &Value) -> Result<Bson, String> {
+    match (field_type, value) {
+        (FieldType::String, Value::String(value)) => Ok(Bson::String(value.clone())),
+        (FieldType::Age, Value::Number(number)) => number
+            .as_u64()
+            .filter(|age| *age <= u8::MAX as u64)
+            .map(|age| Bson::Int32(age as i32))
+            .ok_or_else(|| format!("'{}' must be an integer between 0 and 255", field)),
+        (FieldType::String, _) => Err(format!("'{}' must be compared to a string", field)),
+        (FieldType::Age, _) => Err(format!("'{}' must be compared to a number", field)),
+    }
+}
+
+/// Translates the DSL into a Mongo filter, rejecting unknown fields, unknown
+/// operators and mistyped values so no raw Mongo syntax reaches the database.
+pub fn to_filter(query: &QueryDsl) -> Result<Document, String> {
+    let mut filter = Document::new();
+
+    for (field, operators) in query {
+        let field_type = field_type(field).ok_or_else(|| format!("Unknown field '{}'", field))?;
+        if operators.is_empty() {
+            return Err(format!("No operators given for '{}'", field));
+        }
+
+        let mut conditions = Document::new();
+        for (operator, value) in operators {
+            let mongo_operator = mongo_operator(operator)
+                .ok_or_else(|| format!("Unknown operator '{}' on '{}'", operator, field))?;
+
+            let value = match (mongo_operator, value) {
+                ("$in", Value::Array(values)) => Bson::Array(
+                    values
+                        .iter()
+                        .map(|value| to_bson(field, field_type, value))
+                        .collect::<Result<Vec<Bson>, String>>()?,
+                ),
+                ("$in", _) => return Err(format!("'in' on '{}' expects an array", field)),
+                (_, value) => to_bson(field, field_type, value)?,
+            };
+            conditions.insert(mongo_operator, value);
+        }
+        // Identities without an age never match an age condition, not even `ne`.
+        if matches!(field_type, FieldType::Age) {
+            conditions.insert("$exists", true);
+        }
+        filter.insert(field.as_str(), conditions);
+    }
+
+    Ok(filter)
+}
+
+/// How `POST /identity/by-labels` combines the requested labels.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelMatch {
+    All,
+    Any,
+}
+
+/// Body of `POST /identity/by-labels`, e.g. `{ "labels": ["vip"], "match": "any" }`.
+#[derive(Debug, Deserialize)]
+pub struct LabelQuery {
+    pub labels: Vec<String>,
+    #[serde(rename = "match")]
+    pub mode: LabelMatch,
+}
+
+impl LabelQuery {
+    /// `$all` when an identity must carry every label, `$in` when any one suffices.
+    pub fn to_filter(&self) -> Result<Document, String> {
+        if self.labels.is_empty() {
+            return Err("'labels' must not be empty".to_string());
+        }
+        let operator = match self.mode {
+            LabelMatch::All => "$all",
+            LabelMatch::Any => "$in",
+        };
+        Ok(doc! { "labels": { operator: &self.labels } })
+    }
+}
+
+/// `Query` extractor that answers `400` when a parameter is repeated, since
+/// `?page=1&page=2` would otherwise silently resolve to one of the values.
+/// No endpoint currently accepts multi-valued parameters.
+pub struct StrictQuery<T>(pub T);
+
+impl<T, S> FromRequestParts<S> for StrictQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Query(pairs) = Query::<Vec<(String, String)>>::try_from_uri(&parts.uri)
+            .map_err(IntoResponse::into_response)?;
+
+        let mut seen = HashSet::new();
+        if let Some((key, _)) = pairs.iter().find(|(key, _)| !seen.insert(key.as_str())) {
+            return Err(AppError::BadRequest(format!(
+                "Query parameter '{}' must not be repeated",
+                key
+            ))
+            .into_response());
+        }
+
+        let Query(params) =
+            Query::<T>::try_from_uri(&parts.uri).map_err(IntoResponse::into_response)?;
+        Ok(StrictQuery(params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::http::{Request, StatusCode};
+    use serde_json::json;
+
+    fn query(value: Value) -> QueryDsl {
+        serde_json::from_value(value).unwrap()
+    }
+
+    async fn strict_query<T: DeserializeOwned>(uri: &str) -> Result<T, Response> {
+        let (mut parts, ()) = Request::builder().uri(uri).body(()).unwrap().into_parts();
+        StrictQuery::<T>::from_request_parts(&mut parts, &())
+            .await
+            .map(|StrictQuery(params)| params)
+    }
+
+    #[tokio::test]
+    async fn strict_query_rejects_repeated_parameters() {
+        let rejection = strict_query::<ListParams>("/identity?page=1&per_page=5&page=2")
+            .await
+            .unwrap_err();
+        assert_eq!(rejection.status(), StatusCode::BAD_REQUEST);
+
+        let params = strict_query::<ListParams>("/identity?page=2&per_page=5")
+            .await
+            .unwrap();
+        assert_eq!((params.page, params.per_page), (Some(2), Some(5)));
+    }
+
+    #[test]
+    fn validate_points_deep_offsets_to_keyset_pagination() {
+        let per_page = *MAX_PAGE_SIZE;
+        let last_page = *MAX_PAGINATION_OFFSET / per_page + 1;
+        let params = |page| ListParams {
+            page: Some(page),
+            per_page: Some(per_page),
+            ..ListParams::default()
+        };
+
+        assert_eq!(params(last_page).validate(), Ok(()));
+        let error = params(last_page + 1).validate().unwrap_err();
+        assert!(error.contains(&format!("maximum of {}", *MAX_PAGINATION_OFFSET)));
+        assert!(error.contains("pass the last _id you received as 'after'"));
+
+        let keyset = ListParams {
+            after: Some(ObjectId::new()),
+            per_page: Some(per_page),
+            ..ListParams::default()
+        };
+        assert_eq!(keyset.validate(), Ok(()));
+    }
+
+    #[test]
+    fn to_filter_translates_known_operators() {
+        let filter = to_filter(&query(json!({
+            "name": { "in": ["Alice", "Bob"], "ne": "Carol" },
+            "age": { "gte": 18, "lt": 65 },
+        })))
+        .unwrap();
+
+        assert_eq!(
+            filter,
+            doc! {
+                "age": { "$gte": 18, "$lt": 65, "$exists": true },
+                "name": { "$in": ["Alice", "Bob"], "$ne": "Carol" },
+            }
+        );
+    }
+
+    #[test]
+    fn to_filter_rejects_unknown_or_mistyped_input() {
+        for (input, error) in [
+            (
+                json!({ "name": { "$where": "sleep(1000)" } }),
+                "Unknown operator '$where' on 'name'",
+            ),
+            (
+                json!({ "name": { "regex": ".*" } }),
+                "Unknown operator 'regex' on 'name'",
+            ),
+            (json!({ "$or": { "eq": 1 } }), "Unknown field '$or'"),
+            (json!({ "name": {} }), "No operators given for 'name'"),
+            (
+                json!({ "name": { "in": "Alice" } }),
+                "'in' on 'name' expects an array",
+            ),
+            (
+                json!({ "age": { "eq": "18" } }),
+                "'age' must be compared to a number",
+            ),
+            (
+                json!({ "age": { "gt": 300 } }),
+                "'age' must be an integer between 0 and 255",
+            ),
+        ] {
+            assert_eq!(to_filter(&query(input)).unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn label_query_uses_all_or_in() {
+        let label_query = |value: Value| -> LabelQuery { serde_json::from_value(value).unwrap() };
+
+        assert_eq!(
+            label_query(json!({ "labels": ["a", "b"], "match": "all" })).to_filter(),
+            Ok(doc! { "labels": { "$all": ["a", "b"] } })
+        );
+        assert_eq!(
+            label_query(json!({ "labels": ["a", "b"], "match": "any" })).to_filter(),
+            Ok(doc! { "labels": { "$in": ["a", "b"] } })
+        );
+        assert_eq!(
+            label_query(json!({ "labels": [], "match": "any" })).to_filter(),
+            Err("'labels' must not be empty".to_string())
+        );
+    }
+}
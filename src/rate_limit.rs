@@ -0,0 +1,161 @@
+use crate::ApiResponse;
+
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Number of tracked keys above which expired windows are pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Fixed-window request counter keyed by an arbitrary string.
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset_after: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        RateLimiter {
+            limit,
+            window,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limit > 0
+    }
+
+    /// Counts one request for `key` and reports whether it fits in the current window.
+    pub fn check(&self, key: &str) -> RateLimitDecision {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+
+        if windows.len() > PRUNE_THRESHOLD {
+            windows.retain(|_, (started, _)| now.duration_since(*started) < self.window);
+        }
+
+        let (started, count) = windows.entry(key.to_string()).or_insert((now, 0));
+        if now.duration_since(*started) >= self.window {
+            *started = now;
+            *count = 0;
+        }
+        *count = count.saturating_add(1);
+
+        RateLimitDecision {
+            allowed: *count <= self.limit,
+            limit: self.limit,
+            remaining: self.limit.saturating_sub(*count),
+            reset_after: self.window.saturating_sub(now.duration_since(*started)),
+        }
+    }
+}
+
+impl RateLimitDecision {
+    fn apply_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("X-RateLimit-Limit", HeaderValue::from(self.limit));
+        headers.insert("X-RateLimit-Remaining", HeaderValue::from(self.remaining));
+        headers.insert(
+            "X-RateLimit-Reset",
+            HeaderValue::from(self.reset_after.as_secs()),
+        );
+    }
+}
+
+/// Throttles requests per authenticated user. Must run after `login_required`,
+/// which stores the caller's email (the token `sub`) in the request extensions.
+pub async fn user_rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(email) = req.extensions().get::<String>().cloned() else {
+        return next.run(req).await;
+    };
+    if !limiter.is_enabled() {
+        return next.run(req).await;
+    }
+
+    let decision = limiter.check(&email);
+    if !decision.allowed {
+        let response_data = ApiResponse::new("Too many requests", ());
+        let mut response = (StatusCode::TOO_MANY_REQUESTS, Json(response_data)).into_response();
+        decision.apply_headers(response.headers_mut());
+        response.headers_mut().insert(
+            "Retry-After",
+            HeaderValue::from(decision.reset_after.as_secs().max(1)),
+        );
+        return response;
+    }
+
+    let mut response = next.run(req).await;
+    decision.apply_headers(response.headers_mut());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{Extension, Router, body::Body, middleware::from_fn_with_state, routing::get};
+    use tower::ServiceExt;
+
+    #[test]
+    fn check_counts_each_key_separately() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+
+        let first = limiter.check("a@example.com");
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert!(limiter.check("a@example.com").allowed);
+
+        let third = limiter.check("a@example.com");
+        assert!(!third.allowed);
+        assert_eq!(third.remaining, 0);
+        assert!(third.reset_after <= Duration::from_secs(60));
+
+        assert!(limiter.check("b@example.com").allowed);
+    }
+
+    #[tokio::test]
+    async fn answers_429_with_rate_limit_headers() {
+        let limiter = Arc::new(RateLimiter::new(1, Duration::from_secs(60)));
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route_layer(from_fn_with_state(limiter, user_rate_limit))
+            .layer(Extension("user@example.com".to_string()));
+
+        let response = app
+            .clone()
+            .oneshot(Request::new(Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-RateLimit-Limit"], "1");
+        assert_eq!(response.headers()["X-RateLimit-Remaining"], "0");
+
+        let response = app.oneshot(Request::new(Body::empty())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["X-RateLimit-Limit"], "1");
+        assert_eq!(response.headers()["X-RateLimit-Remaining"], "0");
+        assert!(response.headers().contains_key("X-RateLimit-Reset"));
+        assert!(response.headers().contains_key("Retry-After"));
+    }
+}
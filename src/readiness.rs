@@ -0,0 +1,111 @@
+use crate::ApiResponse;
+use crate::config::STARTUP_GATE;
+
+use axum::{
+    Json,
+    extract::Request,
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Process-wide startup state, advanced by `main` and reported at `/ready`.
+pub static READINESS: Lazy<Readiness> = Lazy::new(Readiness::default);
+
+/// Steps the server goes through after it starts listening. Only `Ready`
+/// means it can serve traffic; a `Failed` startup shuts the server down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StartupPhase {
+    Starting,
+    WarmingPool,
+    CheckingIndexes,
+    Ready,
+    Failed,
+}
+
+impl StartupPhase {
+    const ALL: [StartupPhase; 5] = [
+        StartupPhase::Starting,
+        StartupPhase::WarmingPool,
+        StartupPhase::CheckingIndexes,
+        StartupPhase::Ready,
+        StartupPhase::Failed,
+    ];
+}
+
+#[derive(Default)]
+pub struct Readiness {
+    phase: AtomicU8,
+}
+
+impl Readiness {
+    pub fn phase(&self) -> StartupPhase {
+        StartupPhase::ALL[self.phase.load(Ordering::Acquire) as usize]
+    }
+
+    pub fn set(&self, phase: StartupPhase) {
+        self.phase.store(phase as u8, Ordering::Release);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.phase() == StartupPhase::Ready
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ReadinessReport {
+    phase: StartupPhase,
+}
+
+/// `200` once startup tasks (pool warmup, index checks) have finished and
+/// `503` before that, so load balancers hold traffic back until then.
+pub async fn ready() -> Response {
+    let phase = READINESS.phase();
+    let (status, message) = if phase == StartupPhase::Ready {
+        (StatusCode::OK, "Ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "Not ready")
+    };
+    (
+        status,
+        Json(ApiResponse::new(message, ReadinessReport { phase })),
+    )
+        .into_response()
+}
+
+/// In `STARTUP_GATE` mode, answers `503 Service Unavailable` until startup has
+/// finished, instead of letting requests reach an unprepared database.
+pub async fn gate_until_ready(req: Request, next: Next) -> Response {
+    if !*STARTUP_GATE || READINESS.is_ready() {
+        return next.run(req).await;
+    }
+
+    let response_data = ApiResponse::new("Server is starting", ());
+    let mut response = (StatusCode::SERVICE_UNAVAILABLE, Json(response_data)).into_response();
+    response
+        .headers_mut()
+        .insert("Retry-After", HeaderValue::from_static("1"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readiness_moves_through_phases() {
+        let readiness = Readiness::default();
+        assert_eq!(readiness.phase(), StartupPhase::Starting);
+        assert!(!readiness.is_ready());
+
+        for phase in StartupPhase::ALL {
+            readiness.set(phase);
+            assert_eq!(readiness.phase(), phase);
+            assert_eq!(readiness.is_ready(), phase == StartupPhase::Ready);
+        }
+    }
+}
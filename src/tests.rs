@@ -0,0 +1,1827 @@
+//! Route tests, run against the full `app` router. Tests that need MongoDB
+//! use a throwaway database on the server at `TEST_MONGO_URI` and are ignored
+//! unless run with `cargo test -- --ignored`.
+
+use super::*;
+use crate::config::init_test_env;
+use crate::request_id::REQUEST_ID_HEADER;
+
+use axum::{
+    body::{Body, Bytes, to_bytes},
+    extract::ConnectInfo,
+    http::{
+        Request as HttpRequest,
+        header::{
+            ACCEPT, ACCEPT_LANGUAGE, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+            CONTENT_LANGUAGE, HOST as HOST_HEADER, ORIGIN,
+        },
+    },
+};
+use mongodb::error::ErrorKind;
+use serde_json::{Value, json};
+use std::{fmt, sync::Mutex};
+use tower::ServiceExt;
+use tracing::{
+    Event as TracingEvent, Level, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{
+    Registry,
+    layer::{Context, Layer, SubscriberExt},
+};
+
+/// Held by tests that move the process-wide `READINESS`.
+static READINESS_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Level and fields of the log events emitted while it is installed with
+/// `tracing::subscriber::set_default`.
+#[derive(Clone, Default)]
+struct CapturedEvents(Arc<Mutex<Vec<LoggedEvent>>>);
+
+type LoggedEvent = (Level, BTreeMap<String, String>);
+
+impl CapturedEvents {
+    fn install(&self) -> tracing::subscriber::DefaultGuard {
+        tracing::subscriber::set_default(Registry::default().with(self.clone()))
+    }
+
+    /// Events whose message is `message`.
+    fn named(&self, message: &str) -> Vec<LoggedEvent> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, fields)| fields.get("message").map(String::as_str) == Some(message))
+            .cloned()
+            .collect()
+    }
+}
+
+impl<S: Subscriber> Layer<S> for CapturedEvents {
+    fn on_event(&self, event: &TracingEvent<'_>, _ctx: Context<'_, S>) {
+        struct Fields(BTreeMap<String, String>);
+        impl Visit for Fields {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.0.insert(field.name().to_string(), value.to_string());
+            }
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0
+                    .insert(field.name().to_string(), format!("{:?}", value));
+            }
+        }
+
+        let mut fields = Fields(BTreeMap::new());
+        event.record(&mut fields);
+        self.0
+            .lock()
+            .unwrap()
+            .push((*event.metadata().level(), fields.0));
+    }
+}
+
+/// A database on a closed port, for requests that must not reach MongoDB or
+/// must fail when they do.
+async fn unreachable_db() -> Database {
+    init_test_env();
+    Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+        .await
+        .expect("valid URI")
+        .database("restful_axum_test")
+}
+
+/// A fresh database on the server at `TEST_MONGO_URI`. Its client reports to
+/// `METRICS` like the server's own.
+async fn live_db() -> Database {
+    init_test_env();
+    let uri = std::env::var("TEST_MONGO_URI").expect("TEST_MONGO_URI env not set.");
+    let options = client_options(&uri)
+        .await
+        .expect("TEST_MONGO_URI is not a valid URI");
+    let client = Client::with_options(options).expect("valid client options");
+    client.database(&format!("restful_axum_test_{}", ObjectId::new()))
+}
+
+fn test_app(database: &Database) -> Router {
+    app(
+        init_identity_collection(database),
+        init_auth_collection(database),
+        init_audit_collection(database),
+        database.clone(),
+    )
+}
+
+async fn send(app: &Router, request: HttpRequest<Body>) -> Response {
+    app.clone().oneshot(request).await.expect("infallible")
+}
+
+async fn json_body(response: Response) -> Value {
+    let bytes = to_bytes(response.into_body(), usize::MAX)
+        .await
+        .expect("readable body");
+    serde_json::from_slice(&bytes).expect("JSON body")
+}
+
+fn request(
+    method: Method,
+    uri: &str,
+    token: Option<&str>,
+    body: Option<Value>,
+) -> HttpRequest<Body> {
+    let mut builder = HttpRequest::builder().method(method).uri(uri);
+    if let Some(token) = token {
+        builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    match body {
+        Some(body) => {
+            let body = body.to_string();
+            builder
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_LENGTH, body.len())
+                .body(Body::from(body))
+        }
+        None => builder.body(Body::empty()),
+    }
+    .expect("valid request")
+}
+
+/// Registers `email` without going through `/signup`, which hashes with
+/// Argon2, and returns a token for it.
+async fn sign_in(database: &Database, email: &str) -> String {
+    init_auth_collection(database)
+        .insert_one(Auth {
+            email: email.to_string(),
+            password: String::new(),
+            pending_email: None,
+            pending_email_token: None,
+            pending_email_expires_at: None,
+            token_version: 0,
+        })
+        .await
+        .expect("inserted account");
+    generate_token(email, 0).expect("signed token")
+}
+
+/// Creates an identity through `POST /identity` and returns its id.
+async fn post_identity(app: &Router, token: &str, identity: Value) -> ObjectId {
+    let response = send(
+        app,
+        request(Method::POST, "/identity", Some(token), Some(identity)),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let id = json_body(response).await["data"]["$oid"].clone();
+    ObjectId::parse_str(id.as_str().expect("inserted id")).expect("valid ObjectId")
+}
+
+/// Names of the identities listed in a response body, in order.
+fn names(body: &Value) -> Vec<&str> {
+    body["data"]
+        .as_array()
+        .expect("identities")
+        .iter()
+        .map(|identity| identity["name"].as_str().expect("name"))
+        .collect()
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn validate_reports_errors_by_index_without_inserting() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "validator@example.com").await;
+
+    let batch = json!([
+        { "name": "Alice", "age": 30 },
+        { "name": " ", "age": 30 },
+        { "name": "Bob", "age": "thirty" },
+        { "name": "Carol", "age": 41 },
+    ]);
+    let response = send(
+        &app,
+        request(
+            Method::POST,
+            "/identity/validate",
+            Some(&token),
+            Some(batch),
+        ),
+    )
+    .await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = json_body(response).await;
+    assert_eq!(body["message"], "Some identities are invalid");
+    let errors = body["data"].as_array().expect("errors");
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0]["index"], 1);
+    assert_eq!(errors[0]["error"], "Name must not be empty.");
+    assert_eq!(errors[1]["index"], 2);
+    assert!(
+        errors[1]["error"]
+            .as_str()
+            .unwrap()
+            .contains("invalid type")
+    );
+
+    let stored = init_identity_collection(&db)
+        .count_documents(doc! {})
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn patch_rejects_a_blank_name_like_create() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "member@example.com").await;
+    let id = post_identity(&app, &token, json!({ "name": "Alice", "age": 30 })).await;
+    let uri = format!("/identity/{}", id);
+
+    for name in ["", "   "] {
+        let response = send(
+            &app,
+            request(
+                Method::PATCH,
+                &uri,
+                Some(&token),
+                Some(json!({ "name": name })),
+            ),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json_body(response).await["message"],
+            "Name must not be empty."
+        );
+    }
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+async fn disabled_features_are_not_found() {
+    let app = test_app(&unreachable_db().await);
+
+    for method in [Method::GET, Method::POST] {
+        let response = send(&app, request(method, "/identity/events", None, None)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(json_body(response).await["message"], "Not found");
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn diagnostics_redact_secrets() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let admin = sign_in(&db, "admin@example.com").await;
+    let user = sign_in(&db, "user@example.com").await;
+
+    let response = send(
+        &app,
+        request(Method::GET, "/admin/diagnostics", Some(&user), None),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = send(
+        &app,
+        request(Method::GET, "/admin/diagnostics", Some(&admin), None),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = json_body(response).await;
+    let rendered = body.to_string();
+    for secret in ["test-secret", "old-secret", "ops-secret", "hunter2"] {
+        assert!(!rendered.contains(secret), "{} leaked", secret);
+    }
+    assert_eq!(body["data"]["config"]["SECRET_KEY"], "[redacted]");
+    assert_eq!(body["data"]["health"]["database"], "up");
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn email_change_applies_once_confirmed_and_revokes_old_tokens() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let old_token = sign_in(&db, "before@example.com").await;
+    let identities = init_identity_collection(&db);
+    identities
+        .insert_one(Identity {
+            id: None,
+            name: "Alice".to_string(),
+            age: Some(30),
+            owner_email: Some("before@example.com".to_string()),
+            labels: Vec::new(),
+            created_at: Some(DateTime::now()),
+            updated_at: None,
+            deleted_at: None,
+        })
+        .await
+        .unwrap();
+
+    let change = json!({ "email": "after@example.com" });
+    let response = send(
+        &app,
+        request(Method::PATCH, "/me/email", Some(&old_token), Some(change)),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    // Pending: the current address keeps working.
+    let response = send(
+        &app,
+        request(Method::GET, "/protected", Some(&old_token), None),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The emailed token is only logged, so plant a known one.
+    init_auth_collection(&db)
+        .update_one(
+            doc! { "email": "before@example.com" },
+            doc! { "$set": { "pending_email_token": hash_token("known-token") } },
+        )
+        .await
+        .unwrap();
+    let response = send(
+        &app,
+        request(
+            Method::GET,
+            "/auth/confirm-email?token=known-token",
+            None,
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let new_token = json_body(response).await["data"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let response = send(
+        &app,
+        request(Method::GET, "/protected", Some(&old_token), None),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = send(
+        &app,
+        request(Method::GET, "/protected", Some(&new_token), None),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        json_body(response).await["message"],
+        "Hello. You are logged in using after@example.com"
+    );
+
+    // An account taking over the old address starts at another version.
+    sign_in(&db, "before@example.com").await;
+    init_auth_collection(&db)
+        .update_one(
+            doc! { "email": "before@example.com" },
+            doc! { "$set": { "token_version": 7 } },
+        )
+        .await
+        .unwrap();
+    let response = send(
+        &app,
+        request(Method::GET, "/protected", Some(&old_token), None),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let moved = identities
+        .count_documents(doc! { "owner_email": "after@example.com" })
+        .await
+        .unwrap();
+    assert_eq!(moved, 1);
+
+    let response = send(
+        &app,
+        request(
+            Method::GET,
+            "/auth/confirm-email?token=known-token",
+            None,
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn email_change_to_a_registered_email_conflicts() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    ensure_indexes(&init_identity_collection(&db), &init_auth_collection(&db))
+        .await
+        .unwrap();
+    let token = sign_in(&db, "first@example.com").await;
+    sign_in(&db, "taken@example.com").await;
+
+    let change = json!({ "email": "taken@example.com" });
+    let response = send(
+        &app,
+        request(Method::PATCH, "/me/email", Some(&token), Some(change)),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(
+        json_body(response).await["message"],
+        "Email already registered"
+    );
+
+    // An address registered while the change was pending conflicts on confirmation.
+    let change = json!({ "email": "later@example.com" });
+    let response = send(
+        &app,
+        request(Method::PATCH, "/me/email", Some(&token), Some(change)),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    sign_in(&db, "later@example.com").await;
+    init_auth_collection(&db)
+        .update_one(
+            doc! { "email": "first@example.com" },
+            doc! { "$set": { "pending_email_token": hash_token("known-token") } },
+        )
+        .await
+        .unwrap();
+    let response = send(
+        &app,
+        request(
+            Method::GET,
+            "/auth/confirm-email?token=known-token",
+            None,
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+async fn non_ascii_authorization_is_a_bad_request() {
+    let app = test_app(&unreachable_db().await);
+    let mut invalid = request(Method::GET, "/protected", None, None);
+    invalid.headers_mut().insert(
+        AUTHORIZATION,
+        HeaderValue::from_bytes(b"Bearer \xff").unwrap(),
+    );
+
+    let response = send(&app, invalid).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = json_body(response).await;
+    assert_eq!(body["message"], "Invalid Authorization header");
+    assert!(body.get("request_id").is_none());
+}
+
+#[tokio::test]
+async fn server_errors_quote_the_request_id_header() {
+    let app = test_app(&unreachable_db().await);
+    let token = generate_token("user@example.com", 0).unwrap();
+
+    let mut tagged = request(Method::GET, "/protected", Some(&token), None);
+    tagged
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, HeaderValue::from_static("trace-123"));
+    let response = send(&app, tagged).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[REQUEST_ID_HEADER], "trace-123");
+    assert_eq!(json_body(response).await["request_id"], "trace-123");
+
+    // Without one, the generated id is both echoed and quoted.
+    let response = send(&app, request(Method::GET, "/protected", Some(&token), None)).await;
+    let header = response.headers()[REQUEST_ID_HEADER]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(json_body(response).await["request_id"], header);
+}
+
+#[tokio::test]
+async fn content_language_only_names_languages_messages_are_in() {
+    let app = test_app(&unreachable_db().await);
+    let localized = |uri: &str, language: &'static str| {
+        let mut localized = request(Method::GET, uri, None, None);
+        localized
+            .headers_mut()
+            .insert(ACCEPT_LANGUAGE, HeaderValue::from_static(language));
+        localized
+    };
+
+    let response = send(&app, localized("/nowhere", "fr")).await;
+    assert_eq!(response.headers()[CONTENT_LANGUAGE], "fr");
+    assert_eq!(json_body(response).await["message"], "Introuvable");
+
+    // Not in the catalog, so the message stays in English.
+    let response = send(&app, localized("/meta", "fr")).await;
+    assert!(response.headers().get(CONTENT_LANGUAGE).is_none());
+    assert_eq!(json_body(response).await["message"], "Client hints");
+
+    let response = send(&app, localized("/meta", "en")).await;
+    assert_eq!(response.headers()[CONTENT_LANGUAGE], "en");
+}
+
+#[tokio::test]
+async fn cors_exposes_headers_to_allowed_origins_only() {
+    let app = test_app(&unreachable_db().await);
+    let from = |origin: &'static str| {
+        let mut cross_origin = request(Method::GET, "/meta", None, None);
+        cross_origin
+            .headers_mut()
+            .insert(ORIGIN, HeaderValue::from_static(origin));
+        cross_origin
+    };
+
+    let response = send(&app, from("https://app.example.com")).await;
+    assert_eq!(
+        response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+        "https://app.example.com"
+    );
+    let exposed = response.headers()[ACCESS_CONTROL_EXPOSE_HEADERS]
+        .to_str()
+        .unwrap()
+        .to_ascii_lowercase();
+    assert!(
+        exposed
+            .split(',')
+            .any(|header| header.trim() == "x-total-count")
+    );
+
+    let response = send(&app, from("https://elsewhere.example.com")).await;
+    assert!(
+        response
+            .headers()
+            .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none()
+    );
+}
+
+#[tokio::test]
+async fn strict_content_length_refuses_chunked_uploads_only() {
+    let app = test_app(&unreachable_db().await);
+    let signup = |headers: &[(HeaderName, &'static str)]| {
+        let mut signup = request(Method::POST, "/signup", None, Some(json!({})));
+        signup.headers_mut().remove(CONTENT_LENGTH);
+        for (name, value) in headers {
+            signup
+                .headers_mut()
+                .insert(name, HeaderValue::from_static(value));
+        }
+        signup
+    };
+
+    let response = send(&app, signup(&[(TRANSFER_ENCODING, "chunked")])).await;
+    assert_eq!(response.status(), StatusCode::LENGTH_REQUIRED);
+
+    // Past the check, the incomplete credentials fail validation instead.
+    let response = send(
+        &app,
+        signup(&[(TRANSFER_ENCODING, "chunked"), (CONTENT_LENGTH, "2")]),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let response = send(&app, signup(&[(CONTENT_LENGTH, "2")])).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn strict_content_length_refuses_streamed_bodies_of_unknown_length() {
+    let app = test_app(&unreachable_db().await);
+    let signup = |body: Body| {
+        HttpRequest::builder()
+            .method(Method::POST)
+            .uri("/signup")
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .expect("valid request")
+    };
+
+    // Framed like an HTTP/2 upload: no Content-Length and no Transfer-Encoding.
+    let chunks = futures::stream::iter([Ok::<_, Infallible>("{}")]);
+    let response = send(&app, signup(Body::from_stream(chunks))).await;
+    assert_eq!(response.status(), StatusCode::LENGTH_REQUIRED);
+
+    // A body known to be empty needs no length; it then fails to parse.
+    let response = send(&app, signup(Body::empty())).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn repeated_page_parameter_is_rejected() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "pager@example.com").await;
+
+    let response = send(
+        &app,
+        request(Method::GET, "/identity?page=1&page=2", Some(&token), None),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        json_body(response).await["message"],
+        "Query parameter 'page' must not be repeated"
+    );
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+async fn ready_reports_503_until_startup_finishes() {
+    let _readiness = READINESS_LOCK.lock().await;
+    let app = test_app(&unreachable_db().await);
+
+    READINESS.set(StartupPhase::WarmingPool);
+    let response = send(&app, request(Method::GET, "/ready", None, None)).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(json_body(response).await["data"]["phase"], "warming_pool");
+
+    READINESS.set(StartupPhase::Ready);
+    let response = send(&app, request(Method::GET, "/ready", None, None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json_body(response).await["data"]["phase"], "ready");
+}
+
+#[tokio::test]
+async fn failed_startup_stops_the_server_and_closes_the_client() {
+    let _readiness = READINESS_LOCK.lock().await;
+    let db = unreachable_db().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    let served = tokio::time::timeout(
+        Duration::from_secs(10),
+        serve(
+            listener,
+            test_app(&db),
+            &db,
+            &init_identity_collection(&db),
+            &init_auth_collection(&db),
+            std::future::pending(),
+        ),
+    )
+    .await
+    .expect("server stopped after the failed startup");
+
+    assert!(served.is_err());
+    assert_eq!(READINESS.phase(), StartupPhase::Failed);
+    let closed = db.run_command(doc! { "ping": 1 }).await.unwrap_err();
+    assert!(matches!(*closed.kind, ErrorKind::Shutdown));
+}
+
+#[tokio::test]
+async fn warm_pool_fails_without_a_database() {
+    assert!(warm_pool(&unreachable_db().await).await.is_err());
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn warm_pool_pings_the_database() {
+    let db = live_db().await;
+    warm_pool(&db).await.unwrap();
+}
+
+#[tokio::test]
+async fn time_reports_the_current_time() {
+    let app = test_app(&unreachable_db().await);
+
+    let response = send(&app, request(Method::GET, "/time", None, None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let time = json_body(response).await["data"].clone();
+
+    let now = get_current_timestamp();
+    let unix = time["unix"].as_u64().unwrap();
+    assert!(unix.abs_diff(now) <= 5);
+    let utc = DateTime::parse_rfc3339_str(time["utc"].as_str().unwrap()).unwrap();
+    assert!((utc.timestamp_millis() / 1000).abs_diff(now as i64) <= 5);
+    assert_eq!(time["timezone"], "UTC");
+}
+
+#[tokio::test]
+async fn public_responses_are_cacheable_and_protected_ones_are_not() {
+    let app = test_app(&unreachable_db().await);
+
+    let response = send(&app, request(Method::GET, "/meta", None, None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[CACHE_CONTROL],
+        "public, max-age=60, stale-while-revalidate=30"
+    );
+
+    let response = send(&app, request(Method::GET, "/protected", None, None)).await;
+    assert_eq!(response.headers()[CACHE_CONTROL], "no-store");
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn failed_logins_log_a_warning_with_the_reason() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let credentials = json!({ "email": "known@example.com", "password": "right-password" });
+    let response = send(
+        &app,
+        request(Method::POST, "/signup", None, Some(credentials)),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let events = CapturedEvents::default();
+    let _subscriber = events.install();
+    for credentials in [
+        json!({ "email": "unknown@example.com", "password": "right-password" }),
+        json!({ "email": "known@example.com", "password": "wrong-password" }),
+    ] {
+        let response = send(
+            &app,
+            request(Method::POST, "/login", None, Some(credentials)),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let logins = events.named("login");
+    assert_eq!(logins.len(), 2);
+    for ((level, fields), reason) in logins.iter().zip(["unknown_email", "invalid_password"]) {
+        assert_eq!(*level, Level::WARN);
+        assert_eq!(fields["outcome"], "failure");
+        assert_eq!(fields["reason"], reason);
+        assert!(fields["email"].starts_with("sha256:"));
+    }
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+async fn root_route_is_not_found_when_disabled() {
+    let app = test_app(&unreachable_db().await);
+
+    let response = send(&app, request(Method::GET, "/", None, None)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(json_body(response).await["message"], "Not found");
+}
+
+#[tokio::test]
+async fn plain_http_reads_redirect_and_writes_are_refused() {
+    let app = test_app(&unreachable_db().await);
+    let forwarded = |method: Method, uri: &str, proto: &'static str| {
+        let mut forwarded = request(method, uri, None, None);
+        let headers = forwarded.headers_mut();
+        headers.insert("X-Forwarded-Proto", HeaderValue::from_static(proto));
+        headers.insert(HOST_HEADER, HeaderValue::from_static("evil.example.com"));
+        headers.insert(
+            "X-Forwarded-Host",
+            HeaderValue::from_static("evil.example.com"),
+        );
+        forwarded
+    };
+
+    let response = send(&app, forwarded(Method::GET, "/meta?lang=en", "http")).await;
+    assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(
+        response.headers()[LOCATION],
+        "https://api.example.com/meta?lang=en"
+    );
+
+    let response = send(&app, forwarded(Method::POST, "/signup", "http")).await;
+    assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+
+    let response = send(&app, forwarded(Method::GET, "/meta", "https")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn unknown_email_and_wrong_password_look_the_same() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let credentials = json!({ "email": "member@example.com", "password": "right-password" });
+    let response = send(
+        &app,
+        request(Method::POST, "/signup", None, Some(credentials)),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let mut failures = Vec::new();
+    for credentials in [
+        json!({ "email": "stranger@example.com", "password": "right-password" }),
+        json!({ "email": "member@example.com", "password": "wrong-password" }),
+    ] {
+        let response = send(
+            &app,
+            request(Method::POST, "/login", None, Some(credentials)),
+        )
+        .await;
+        failures.push((response.status(), json_body(response).await));
+    }
+
+    assert_eq!(failures[0], failures[1]);
+    assert_eq!(failures[0].0, StatusCode::UNAUTHORIZED);
+    assert_eq!(failures[0].1["message"], INVALID_CREDENTIALS);
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn db_stats_reports_pool_figures_to_admins() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let admin = sign_in(&db, "admin@example.com").await;
+    let member = sign_in(&db, "member@example.com").await;
+
+    let response = send(
+        &app,
+        request(Method::GET, "/admin/db-stats", Some(&member), None),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = send(
+        &app,
+        request(Method::GET, "/admin/db-stats", Some(&admin), None),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let stats = json_body(response).await["data"].clone();
+    for field in [
+        "connections_open",
+        "connections_in_use",
+        "connections_idle",
+        "checkouts_total",
+        "checkout_failures_total",
+    ] {
+        assert!(stats[field].is_u64(), "{} missing from {}", field, stats);
+    }
+    db.drop().await.unwrap();
+}
+
+/// Next chunk of an event stream body, as text.
+async fn next_frame(
+    frames: &mut (impl Stream<Item = Result<Bytes, axum::Error>> + Unpin),
+) -> String {
+    let frame = tokio::time::timeout(Duration::from_secs(5), frames.next())
+        .await
+        .expect("a frame before the timeout")
+        .expect("an open stream")
+        .expect("a readable frame");
+    String::from_utf8(frame.to_vec()).expect("UTF-8 frame")
+}
+
+#[tokio::test]
+async fn idle_event_streams_send_keep_alive_comments() {
+    let (_changes, source) =
+        futures::channel::mpsc::unbounded::<Result<IdentityChange, mongodb::error::Error>>();
+    let response = change_events(source, 4, Duration::from_millis(20));
+    assert_eq!(response.headers()[CONTENT_TYPE], "text/event-stream");
+
+    let mut frames = response.into_body().into_data_stream();
+    for _ in 0..2 {
+        assert_eq!(next_frame(&mut frames).await, ": keep-alive\n\n");
+    }
+}
+
+#[test]
+fn tokens_signed_with_a_previous_secret_still_verify() {
+    init_test_env();
+    let sign = |secret: &str| {
+        let claims = Claims {
+            sub: "rotated@example.com".to_string(),
+            exp: get_current_timestamp() + 60,
+            token_version: 0,
+        };
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    };
+
+    let current = verify_token(&sign("test-secret")).unwrap();
+    assert_eq!(current.claims.sub, "rotated@example.com");
+    let previous = verify_token(&sign("old-secret")).unwrap();
+    assert_eq!(previous.claims.sub, "rotated@example.com");
+
+    let error = verify_token(&sign("unknown-secret")).unwrap_err();
+    assert!(matches!(error.kind(), JwtErrorKind::InvalidSignature));
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn delete_is_soft_unless_an_admin_asks_for_hard() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let member = sign_in(&db, "member@example.com").await;
+    let admin = sign_in(&db, "admin@example.com").await;
+    let identities = init_identity_collection(&db);
+
+    let soft = post_identity(&app, &member, json!({ "name": "Soft", "age": 30 })).await;
+    let uri = format!("/identity/{}", soft);
+    let response = send(&app, request(Method::DELETE, &uri, Some(&member), None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = send(&app, request(Method::GET, &uri, Some(&member), None)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let stored = identities
+        .find_one(doc! { "_id": soft })
+        .await
+        .unwrap()
+        .expect("soft-deleted identity is kept");
+    assert!(stored.deleted_at.is_some());
+
+    let hard = post_identity(&app, &member, json!({ "name": "Hard", "age": 30 })).await;
+    let uri = format!("/identity/{}?hard=true", hard);
+    let response = send(&app, request(Method::DELETE, &uri, Some(&member), None)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(
+        identities
+            .find_one(doc! { "_id": hard })
+            .await
+            .unwrap()
+            .is_some()
+    );
+
+    let response = send(&app, request(Method::DELETE, &uri, Some(&admin), None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(
+        identities
+            .find_one(doc! { "_id": hard })
+            .await
+            .unwrap()
+            .is_none()
+    );
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn me_identities_lists_only_the_callers_own() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let alice = sign_in(&db, "alice@example.com").await;
+    let bob = sign_in(&db, "bob@example.com").await;
+    let carol = sign_in(&db, "carol@example.com").await;
+
+    post_identity(&app, &alice, json!({ "name": "Alice One", "age": 30 })).await;
+    post_identity(&app, &alice, json!({ "name": "Alice Two", "age": 31 })).await;
+    post_identity(&app, &bob, json!({ "name": "Bob One", "age": 40 })).await;
+
+    for (token, expected) in [
+        (&alice, vec!["Alice One", "Alice Two"]),
+        (&bob, vec!["Bob One"]),
+        (&carol, vec![]),
+    ] {
+        let response = send(
+            &app,
+            request(Method::GET, "/me/identities", Some(token), None),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(names(&json_body(response).await), expected);
+    }
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn identities_without_an_age_never_match_age_filters() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "member@example.com").await;
+
+    let ageless = post_identity(&app, &token, json!({ "name": "Ageless" })).await;
+    post_identity(&app, &token, json!({ "name": "Young", "age": 20 })).await;
+    post_identity(&app, &token, json!({ "name": "Old", "age": 70 })).await;
+
+    let uri = format!("/identity/{}", ageless);
+    let response = send(&app, request(Method::GET, &uri, Some(&token), None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(json_body(response).await["data"].get("age").is_none());
+
+    for (filter, expected) in [
+        (json!({ "age": { "lt": 50 } }), vec!["Young"]),
+        (json!({ "age": { "ne": 20 } }), vec!["Old"]),
+        (json!({ "age": { "gte": 0 } }), vec!["Young", "Old"]),
+    ] {
+        let response = send(
+            &app,
+            request(Method::POST, "/identity/query", Some(&token), Some(filter)),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(names(&json_body(response).await), expected);
+    }
+    db.drop().await.unwrap();
+}
+
+#[test]
+fn check_labels_dedupes_and_enforces_limits() {
+    init_test_env();
+    let labels = |labels: &[&str]| labels.iter().map(|label| label.to_string()).collect();
+
+    assert_eq!(
+        check_labels(labels(&["vip", "beta", "vip"])),
+        Ok(labels(&["vip", "beta"]))
+    );
+
+    let too_long = "x".repeat(*MAX_LABEL_LENGTH + 1);
+    assert_eq!(
+        check_labels(vec![too_long]),
+        Err(format!(
+            "Labels must be at most {} characters long.",
+            *MAX_LABEL_LENGTH
+        ))
+    );
+
+    let too_many: Vec<String> = (0..=*MAX_LABELS).map(|n| n.to_string()).collect();
+    assert_eq!(
+        check_labels(too_many),
+        Err(format!("At most {} labels are allowed.", *MAX_LABELS))
+    );
+    let repeated = vec!["same".to_string(); *MAX_LABELS + 1];
+    assert_eq!(check_labels(repeated), Ok(labels(&["same"])));
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn only_admins_can_list_deleted_identities() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let member = sign_in(&db, "member@example.com").await;
+    let admin = sign_in(&db, "admin@example.com").await;
+
+    post_identity(&app, &member, json!({ "name": "Kept", "age": 30 })).await;
+    let gone = post_identity(&app, &member, json!({ "name": "Gone", "age": 30 })).await;
+    let uri = format!("/identity/{}", gone);
+    let response = send(&app, request(Method::DELETE, &uri, Some(&member), None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for token in [&member, &admin] {
+        let response = send(&app, request(Method::GET, "/identity", Some(token), None)).await;
+        assert_eq!(names(&json_body(response).await), ["Kept"]);
+    }
+
+    let uri = "/identity?include_deleted=true";
+    let response = send(&app, request(Method::GET, uri, Some(&member), None)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = send(&app, request(Method::GET, uri, Some(&admin), None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = json_body(response).await;
+    assert_eq!(names(&body), ["Kept", "Gone"]);
+    assert!(body["data"][0].get("deletedAt").is_none());
+    assert!(body["data"][1]["deletedAt"].is_string());
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_signal_closes_the_mongodb_client() {
+    let _readiness = READINESS_LOCK.lock().await;
+    let db = unreachable_db().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    // Startup fails against the unreachable database; only the client matters here.
+    let _ = tokio::time::timeout(
+        Duration::from_secs(10),
+        serve(
+            listener,
+            test_app(&db),
+            &db,
+            &init_identity_collection(&db),
+            &init_auth_collection(&db),
+            std::future::ready(()),
+        ),
+    )
+    .await
+    .expect("server stopped on the shutdown signal");
+
+    let closed = db.run_command(doc! { "ping": 1 }).await.unwrap_err();
+    assert!(matches!(*closed.kind, ErrorKind::Shutdown));
+}
+
+#[test]
+fn identity_responses_use_the_configured_field_case() {
+    init_test_env();
+    let now = DateTime::now();
+    let identity = Identity {
+        id: Some(ObjectId::new()),
+        name: "Camel".to_string(),
+        age: Some(30),
+        owner_email: Some("member@example.com".to_string()),
+        labels: vec!["vip".to_string()],
+        created_at: Some(now),
+        updated_at: Some(now),
+        deleted_at: Some(now),
+    };
+
+    let rendered = serde_json::to_value(IdentityResponse::from(identity)).unwrap();
+    let keys: Vec<&str> = rendered
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    assert_eq!(
+        keys,
+        [
+            "_id",
+            "name",
+            "age",
+            "ownerEmail",
+            "labels",
+            "createdAt",
+            "updatedAt",
+            "deletedAt"
+        ]
+    );
+    assert_eq!(rendered["ownerEmail"], "member@example.com");
+}
+
+#[test]
+fn audit_entries_use_the_configured_field_case() {
+    init_test_env();
+    let entry = AuditEntry {
+        id: Some(ObjectId::new()),
+        identity_id: ObjectId::new(),
+        actor: "member@example.com".to_string(),
+        at: DateTime::parse_rfc3339_str("2024-05-01T12:30:00Z").unwrap(),
+        changes: diff(&doc! { "name": "Before" }, &doc! { "name": "After" }),
+    };
+
+    let rendered = serde_json::to_value(AuditEntryResponse::from(entry)).unwrap();
+    let keys: Vec<&str> = rendered
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    assert_eq!(keys, ["_id", "identityId", "actor", "at", "changes"]);
+    assert_eq!(rendered["at"], "2024-05-01T12:30:00Z");
+    assert_eq!(
+        rendered["changes"],
+        json!({ "name": { "before": "Before", "after": "After" } })
+    );
+}
+
+#[tokio::test]
+async fn audit_timestamps_render_in_the_requested_timezone() {
+    init_test_env();
+    let app = Router::new()
+        .route(
+            "/audit",
+            get(|| async {
+                Json(AuditEntryResponse::from(AuditEntry {
+                    id: None,
+                    identity_id: ObjectId::new(),
+                    actor: "member@example.com".to_string(),
+                    at: DateTime::parse_rfc3339_str("2024-05-01T12:30:00Z").unwrap(),
+                    changes: BTreeMap::new(),
+                }))
+            }),
+        )
+        .layer(from_fn(display_timezone));
+
+    let mut audit = request(Method::GET, "/audit", None, None);
+    audit
+        .headers_mut()
+        .insert("X-Timezone", HeaderValue::from_static("+05:30"));
+    let response = send(&app, audit).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json_body(response).await["at"], "2024-05-01T18:00:00+05:30");
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn audit_history_records_before_and_after_values() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "member@example.com").await;
+    let id = post_identity(&app, &token, json!({ "name": "Before", "age": 30 })).await;
+    let uri = format!("/identity/{}", id);
+
+    for (update, message) in [
+        (json!({ "name": "After", "age": 30 }), "Updated"),
+        (json!({ "name": "After" }), "No changes made"),
+    ] {
+        let response = send(
+            &app,
+            request(Method::PATCH, &uri, Some(&token), Some(update)),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["message"], message);
+    }
+
+    let uri = format!("/identity/{}/audit", id);
+    let response = send(&app, request(Method::GET, &uri, Some(&token), None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = json_body(response).await;
+    let entries = body["data"].as_array().expect("audit entries");
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["actor"], "member@example.com");
+    assert_eq!(
+        entries[0]["changes"],
+        json!({ "name": { "before": "Before", "after": "After" } })
+    );
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn identity_names_are_unique_per_owner_only() {
+    let db = live_db().await;
+    ensure_indexes(&init_identity_collection(&db), &init_auth_collection(&db))
+        .await
+        .unwrap();
+    let app = test_app(&db);
+    let alice = sign_in(&db, "alice@example.com").await;
+    let bob = sign_in(&db, "bob@example.com").await;
+
+    post_identity(&app, &alice, json!({ "name": "Shared", "age": 30 })).await;
+    post_identity(&app, &bob, json!({ "name": "Shared", "age": 30 })).await;
+
+    let response = send(
+        &app,
+        request(
+            Method::POST,
+            "/identity",
+            Some(&alice),
+            Some(json!({ "name": "Shared", "age": 31 })),
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(json_body(response).await["message"], DUPLICATE_NAME);
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn a_deleted_identity_frees_its_name() {
+    let db = live_db().await;
+    ensure_indexes(&init_identity_collection(&db), &init_auth_collection(&db))
+        .await
+        .unwrap();
+    let app = test_app(&db);
+    let alice = sign_in(&db, "alice@example.com").await;
+
+    for _ in 0..2 {
+        let id = post_identity(&app, &alice, json!({ "name": "Alice", "age": 30 })).await;
+        let uri = format!("/identity/{}", id);
+        let response = send(&app, request(Method::DELETE, &uri, Some(&alice), None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    post_identity(&app, &alice, json!({ "name": "Alice", "age": 30 })).await;
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+async fn failed_listings_are_errors_not_empty_pages() {
+    let db = unreachable_db().await;
+    let response = identity_page(
+        &init_identity_collection(&db),
+        doc! {},
+        &ListParams::default(),
+        "Fetched all identities",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(!response.headers().contains_key("X-Total-Count"));
+    let body = json_body(response).await;
+    assert_eq!(body["data"], Value::Null);
+    assert!(body.get("total").is_none());
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn empty_listings_are_ok_with_a_zero_total() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "member@example.com").await;
+
+    let response = send(&app, request(Method::GET, "/identity", Some(&token), None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["X-Total-Count"], "0");
+    let body = json_body(response).await;
+    assert_eq!(body["data"], json!([]));
+    assert_eq!(body["total"], 0);
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+async fn verify_batch_reports_each_token_separately() {
+    init_test_env();
+    let sign = |secret: &str, exp: u64| {
+        let claims = Claims {
+            sub: "batch@example.com".to_string(),
+            exp,
+            token_version: 0,
+        };
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    };
+    let now = get_current_timestamp();
+
+    let response = verify_token_batch(Json(vec![
+        generate_token("batch@example.com", 0).unwrap(),
+        "not-a-token".to_string(),
+        sign("unknown-secret", now + 60),
+        sign("test-secret", now - 3600),
+    ]))
+    .await
+    .into_response();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let results = json_body(response).await["data"].clone();
+    assert_eq!(results[0]["valid"], true);
+    assert_eq!(results[0]["claims"]["sub"], "batch@example.com");
+    for (index, error) in [(1, "malformed"), (2, "invalid_signature"), (3, "expired")] {
+        assert_eq!(results[index]["index"], index);
+        assert_eq!(results[index]["valid"], false);
+        assert_eq!(results[index]["error"], error);
+        assert!(results[index].get("claims").is_none());
+    }
+}
+
+#[tokio::test]
+async fn unsatisfiable_accept_headers_get_406() {
+    let app = test_app(&unreachable_db().await);
+    let with_accept = |accept: &str| {
+        HttpRequest::builder()
+            .uri("/meta")
+            .header(ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = send(&app, with_accept("text/html")).await;
+    assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+    let body = json_body(response).await;
+    assert_eq!(body["message"], "Not acceptable");
+    assert_eq!(
+        body["data"],
+        json!(["application/json", "application/problem+json"])
+    );
+
+    let response = send(&app, with_accept("text/html, application/*;q=0.5")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn created_per_day_counts_every_day_in_the_range() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "member@example.com").await;
+
+    let at = |timestamp: &str| DateTime::parse_rfc3339_str(timestamp).unwrap();
+    for (name, created_at, deleted) in [
+        ("First", "2024-03-01T00:00:00Z", false),
+        ("Second", "2024-03-01T23:59:59Z", false),
+        ("Deleted", "2024-03-01T12:00:00Z", true),
+        ("Third", "2024-03-03T08:00:00Z", false),
+        ("Outside", "2024-03-04T00:00:00Z", false),
+    ] {
+        init_identity_collection(&db)
+            .insert_one(Identity {
+                id: None,
+                name: name.to_string(),
+                age: Some(30),
+                owner_email: Some("member@example.com".to_string()),
+                labels: Vec::new(),
+                created_at: Some(at(created_at)),
+                updated_at: Some(at(created_at)),
+                deleted_at: deleted.then(DateTime::now),
+            })
+            .await
+            .unwrap();
+    }
+
+    let uri = "/identity/created-per-day?from=2024-03-01&to=2024-03-03";
+    let response = send(&app, request(Method::GET, uri, Some(&token), None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        json_body(response).await["data"],
+        json!([
+            { "date": "2024-03-01", "count": 2 },
+            { "date": "2024-03-02", "count": 0 },
+            { "date": "2024-03-03", "count": 1 },
+        ])
+    );
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+async fn time_reports_token_expiry_to_signed_in_callers_only() {
+    let app = test_app(&unreachable_db().await);
+    let token = generate_token("member@example.com", 0).unwrap();
+
+    let response = send(&app, request(Method::GET, "/time", Some(&token), None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let expires_in = json_body(response).await["data"]["token_expires_in"]
+        .as_u64()
+        .expect("token_expires_in for a signed-in caller");
+    assert!((3590..=3600).contains(&expires_in));
+
+    for token in [None, Some("not-a-token")] {
+        let response = send(&app, request(Method::GET, "/time", token, None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            json_body(response).await["data"]
+                .get("token_expires_in")
+                .is_none()
+        );
+    }
+}
+
+#[tokio::test]
+async fn slow_event_consumers_get_a_lagged_event() {
+    use futures::SinkExt;
+
+    // With a zero-capacity source, `send` only returns once the change has
+    // been taken and handled, so the test knows what the buffer holds.
+    let (mut changes, source) =
+        futures::channel::mpsc::channel::<Result<IdentityChange, mongodb::error::Error>>(0);
+    let change = |id: i32| {
+        Ok(IdentityChange {
+            operation: OperationType::Insert,
+            id: Some(Bson::Int32(id)),
+            document: None,
+        })
+    };
+    let response = change_events(source, 1, Duration::from_secs(60));
+    let mut frames = response.into_body().into_data_stream();
+
+    // The buffer holds change 1, so changes 2 and 3 are dropped.
+    for id in 1..=3 {
+        changes.send(change(id)).await.unwrap();
+    }
+    assert!(next_frame(&mut frames).await.contains(r#""id":1"#));
+
+    changes.send(change(4)).await.unwrap();
+    assert_eq!(
+        next_frame(&mut frames).await,
+        "event: lagged\ndata: {\"dropped\":2}\n\n"
+    );
+}
+
+#[tokio::test]
+async fn metrics_scrapes_need_the_ops_token() {
+    let app = test_app(&unreachable_db().await);
+
+    for token in [None, Some("wrong-secret"), Some("test-secret")] {
+        let response = send(&app, request(Method::GET, "/metrics", token, None)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+    }
+
+    let response = send(
+        &app,
+        request(Method::GET, "/metrics", Some("ops-secret"), None),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let scrape = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(String::from_utf8_lossy(&scrape).contains("mongodb_pool_connections_open"));
+}
+
+/// The value of the sample `name` in a `/metrics` scrape, or `0` when absent.
+async fn scraped(app: &Router, name: &str) -> f64 {
+    let response = send(
+        app,
+        request(Method::GET, "/metrics", Some("ops-secret"), None),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let scrape = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    String::from_utf8_lossy(&scrape)
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+        .unwrap_or(0.0)
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn requests_move_the_command_and_pool_metrics() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "member@example.com").await;
+    let inserts = "mongodb_command_duration_seconds_count{command=\"insert\"}";
+    let checkouts = "mongodb_pool_checkouts_total";
+    let (inserts_before, checkouts_before) =
+        (scraped(&app, inserts).await, scraped(&app, checkouts).await);
+
+    post_identity(&app, &token, json!({ "name": "Counted", "age": 30 })).await;
+
+    assert!(scraped(&app, inserts).await > inserts_before);
+    assert!(scraped(&app, checkouts).await > checkouts_before);
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+async fn spoofed_private_forwarded_for_does_not_skip_ops_auth() {
+    let app = test_app(&unreachable_db().await);
+    let through_proxy = |forwarded_for: &'static str| {
+        let mut scrape = request(Method::GET, "/metrics", None, None);
+        scrape
+            .headers_mut()
+            .insert("X-Forwarded-For", HeaderValue::from_static(forwarded_for));
+        // The proxy itself sits on the private network.
+        scrape
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 2], 443))));
+        scrape
+    };
+
+    // A public client's own private entry, followed by the address the proxy appended.
+    let response = send(&app, through_proxy("10.0.0.1, 203.0.113.7")).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = send(&app, through_proxy("10.0.0.5")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn by_labels_matches_all_or_any_of_the_labels() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "member@example.com").await;
+
+    for (name, labels) in [
+        ("Both", json!(["vip", "beta"])),
+        ("Vip", json!(["vip"])),
+        ("Neither", json!(["alpha"])),
+    ] {
+        post_identity(
+            &app,
+            &token,
+            json!({ "name": name, "age": 30, "labels": labels }),
+        )
+        .await;
+    }
+
+    for (mode, expected) in [("all", vec!["Both"]), ("any", vec!["Both", "Vip"])] {
+        let query = json!({ "labels": ["vip", "beta"], "match": mode });
+        let response = send(
+            &app,
+            request(
+                Method::POST,
+                "/identity/by-labels",
+                Some(&token),
+                Some(query),
+            ),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(names(&json_body(response).await), expected);
+    }
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn startup_skips_index_creation_when_asked() {
+    let db = live_db().await;
+    let _readiness = READINESS_LOCK.lock().await;
+    let identities = init_identity_collection(&db);
+    let auth = init_auth_collection(&db);
+    let events = CapturedEvents::default();
+
+    {
+        let _guard = events.install();
+        run_startup_tasks(&db, &identities, &auth).await.unwrap();
+    }
+
+    assert_eq!(events.named("Skipping index creation").len(), 1);
+    for indexes in [
+        identities.list_index_names().await,
+        auth.list_index_names().await,
+    ] {
+        // Collections that were never written to have no indexes to list.
+        let indexes = indexes.unwrap_or_default();
+        assert!(indexes.iter().all(|index| index == "_id_"), "{:?}", indexes);
+    }
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+async fn meta_reports_the_configured_limits() {
+    let app = test_app(&unreachable_db().await);
+
+    let response = send(&app, request(Method::GET, "/meta", None, None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        json_body(response).await["data"],
+        json!({
+            "default_page_size": *DEFAULT_PAGE_SIZE,
+            "max_page_size": *MAX_PAGE_SIZE,
+            "max_pagination_offset": *MAX_PAGINATION_OFFSET,
+            "allowed_sort_fields": ALLOWED_SORT_FIELDS,
+            "max_body_bytes": *MAX_BODY_BYTES,
+            "max_batch_size": *MAX_BATCH_SIZE,
+            "max_labels": *MAX_LABELS,
+            "max_label_length": *MAX_LABEL_LENGTH,
+            "user_rate_limit": *USER_RATE_LIMIT,
+            "user_rate_limit_window_secs": *USER_RATE_LIMIT_WINDOW_SECS,
+        })
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn replacing_labels_stores_exactly_the_new_set() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "member@example.com").await;
+    let id = post_identity(
+        &app,
+        &token,
+        json!({ "name": "Labelled", "age": 30, "labels": ["old", "kept"] }),
+    )
+    .await;
+
+    let uri = format!("/identity/{}/labels", id);
+    let labels = json!(["kept", "new", "kept"]);
+    let response = send(&app, request(Method::PUT, &uri, Some(&token), Some(labels))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json_body(response).await["data"], json!(["kept", "new"]));
+
+    let stored = init_identity_collection(&db)
+        .find_one(doc! { "_id": id })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.labels, ["kept", "new"]);
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn adding_a_present_label_or_removing_an_absent_one_changes_nothing() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "member@example.com").await;
+    let id = post_identity(
+        &app,
+        &token,
+        json!({ "name": "Labelled", "age": 30, "labels": ["vip"] }),
+    )
+    .await;
+
+    for (method, label) in [(Method::POST, "vip"), (Method::DELETE, "absent")] {
+        let uri = format!("/identity/{}/labels/{}", id, label);
+        let response = send(&app, request(method, &uri, Some(&token), None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["data"], json!(["vip"]));
+    }
+
+    let stored = init_identity_collection(&db)
+        .find_one(doc! { "_id": id })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.labels, ["vip"]);
+    let audited = init_audit_collection(&db)
+        .count_documents(doc! { "identity_id": id })
+        .await
+        .unwrap();
+    assert_eq!(audited, 0);
+    db.drop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn concurrent_signups_for_one_email_create_one_account() {
+    let db = live_db().await;
+    ensure_indexes(&init_identity_collection(&db), &init_auth_collection(&db))
+        .await
+        .unwrap();
+    let app = test_app(&db);
+    let signup = |password: &str| {
+        let app = app.clone();
+        let credentials = json!({ "email": "racer@example.com", "password": password });
+        tokio::spawn(async move {
+            send(
+                &app,
+                request(Method::POST, "/signup", None, Some(credentials)),
+            )
+            .await
+            .status()
+        })
+    };
+
+    let (first, second) = tokio::join!(signup("first-password"), signup("second-password"));
+    let mut statuses = [first.unwrap(), second.unwrap()];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::CREATED, StatusCode::CONFLICT]);
+
+    let accounts = init_auth_collection(&db)
+        .count_documents(doc! { "email": "racer@example.com" })
+        .await
+        .unwrap();
+    assert_eq!(accounts, 1);
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn put_with_if_none_match_only_creates() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "member@example.com").await;
+    let id = ObjectId::new();
+    let uri = format!("/identity/{}", id);
+    let create_only = |name: &str| {
+        let mut put = request(
+            Method::PUT,
+            &uri,
+            Some(&token),
+            Some(json!({ "name": name, "age": 30 })),
+        );
+        put.headers_mut()
+            .insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+        put
+    };
+
+    let response = send(&app, create_only("Original")).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = send(&app, create_only("Retried")).await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+    let stored = init_identity_collection(&db)
+        .find_one(doc! { "_id": id })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.name, "Original");
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn put_renaming_into_a_sibling_name_is_a_duplicate_name() {
+    let db = live_db().await;
+    ensure_indexes(&init_identity_collection(&db), &init_auth_collection(&db))
+        .await
+        .unwrap();
+    let app = test_app(&db);
+    let token = sign_in(&db, "member@example.com").await;
+    post_identity(&app, &token, json!({ "name": "Alice", "age": 30 })).await;
+    let bob = post_identity(&app, &token, json!({ "name": "Bob", "age": 30 })).await;
+
+    let response = send(
+        &app,
+        request(
+            Method::PUT,
+            &format!("/identity/{}", bob),
+            Some(&token),
+            Some(json!({ "name": "Alice", "age": 30 })),
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(json_body(response).await["message"], DUPLICATE_NAME);
+    db.drop().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires TEST_MONGO_URI"]
+async fn identical_put_to_an_identity_without_labels_changes_nothing() {
+    let db = live_db().await;
+    let app = test_app(&db);
+    let token = sign_in(&db, "member@example.com").await;
+    let identity = json!({ "name": "Alice", "age": 30 });
+    let id = post_identity(&app, &token, identity.clone()).await;
+
+    let response = send(
+        &app,
+        request(
+            Method::PUT,
+            &format!("/identity/{}", id),
+            Some(&token),
+            Some(identity),
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json_body(response).await["message"], "No changes made");
+    let audited = init_audit_collection(&db)
+        .count_documents(doc! { "identity_id": id })
+        .await
+        .unwrap();
+    assert_eq!(audited, 0);
+    db.drop().await.unwrap();
+}
@@ -0,0 +1,90 @@
+use crate::ApiResponse;
+use crate::config::{DISPLAY_TIMEZONE, parse_utc_offset};
+
+use axum::{
+    Json,
+    extract::Request,
+    http::{HeaderValue, StatusCode, header::VARY},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use mongodb::bson::DateTime;
+use std::future::Future;
+
+pub const TIMEZONE_HEADER: &str = "X-Timezone";
+
+tokio::task_local! {
+    /// Offset from UTC, in minutes, that timestamps are rendered in.
+    static OFFSET_MINUTES: i32;
+}
+
+fn current_offset() -> i32 {
+    OFFSET_MINUTES
+        .try_with(|offset| *offset)
+        .unwrap_or(*DISPLAY_TIMEZONE)
+}
+
+/// Renders `at` as RFC 3339 in the timezone chosen for the current request,
+/// e.g. `2024-05-01T14:30:00+02:00`. Storage always stays UTC.
+pub fn display_time(at: &DateTime) -> Option<String> {
+    let offset = current_offset();
+    let shifted = DateTime::from_millis(at.timestamp_millis() + i64::from(offset) * 60_000);
+    let utc = shifted.try_to_rfc3339_string().ok()?;
+    if offset == 0 {
+        return Some(utc);
+    }
+
+    let sign = if offset < 0 { '-' } else { '+' };
+    let (hours, minutes) = (offset.abs() / 60, offset.abs() % 60);
+    let local = utc.strip_suffix('Z')?;
+    Some(format!("{}{}{:02}:{:02}", local, sign, hours, minutes))
+}
+
+/// Runs `future`, such as a task spawned by a handler, in the timezone of the
+/// current request.
+pub fn in_current_timezone<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    OFFSET_MINUTES.scope(current_offset(), future)
+}
+
+/// Renders response timestamps in the timezone given by the `X-Timezone`
+/// header, or `DISPLAY_TIMEZONE` without one. An unparsable header is a `400`.
+pub async fn display_timezone(req: Request, next: Next) -> Response {
+    let offset = match req.headers().get(TIMEZONE_HEADER) {
+        None => *DISPLAY_TIMEZONE,
+        Some(value) => match value.to_str().ok().and_then(parse_utc_offset) {
+            Some(offset) => offset,
+            None => {
+                let response_data = ApiResponse::new(
+                    "Invalid X-Timezone header; expected UTC or an offset such as +05:30",
+                    (),
+                );
+                return (StatusCode::BAD_REQUEST, Json(response_data)).into_response();
+            }
+        },
+    };
+
+    let mut response = OFFSET_MINUTES.scope(offset, next.run(req)).await;
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("x-timezone"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_time_renders_in_the_request_offset() {
+        let at = DateTime::parse_rfc3339_str("2024-05-01T12:30:00Z").unwrap();
+
+        for (offset, rendered) in [
+            (0, "2024-05-01T12:30:00Z"),
+            (330, "2024-05-01T18:00:00+05:30"),
+            (-780, "2024-04-30T23:30:00-13:00"),
+        ] {
+            let shown = OFFSET_MINUTES.sync_scope(offset, || display_time(&at));
+            assert_eq!(shown.as_deref(), Some(rendered));
+        }
+    }
+}